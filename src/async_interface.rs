@@ -0,0 +1,380 @@
+//! Async facade over [`HWIClient`].
+//!
+//! Device operations block, both on HWI itself and on the user confirming them on the device.
+//! [`AsyncHWIClient`] moves the [`HWIClient`] to a dedicated worker thread and hands out futures
+//! resolving once the worker has run the corresponding call, so that it can be used from an async
+//! runtime without blocking it. The futures don't depend on any specific runtime.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use bitcoin::bip32::DerivationPath;
+use bitcoin::{Network, Psbt};
+
+use serde::de::DeserializeOwned;
+
+use crate::bip322::Bip322Format;
+use crate::error::Error;
+use crate::interface::HWIClient;
+use crate::types::{
+    HWIAddress, HWIAddressType, HWIChain, HWIDescriptor, HWIDevice, HWIDeviceType,
+    HWIExtendedPubKey, HWIImplementation, HWIKeyPoolElement, HWIPartiallySignedTransaction,
+    HWIPassphrase, HWISignature, HWIWalletPolicy, HWIWordCount, KeypoolRequest, ToDescriptor,
+};
+
+type Job<T> = Box<dyn FnOnce(&HWIClient<T>) + Send>;
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[derive(Debug)]
+struct Shared<R> {
+    result: Option<Result<R, Error>>,
+    waker: Option<Waker>,
+    done: bool,
+    cancelled: bool,
+}
+
+/// Future resolving to the result of a call run by an [`AsyncHWIClient`].
+///
+/// Dropping the future before the worker has started the call cancels it. Once started, the call
+/// runs to completion, as HWI can't interrupt a command waiting for the device.
+#[derive(Debug)]
+pub struct HWIFuture<R> {
+    shared: Arc<Mutex<Shared<R>>>,
+}
+
+/// Sending half of an [`HWIFuture`].
+struct Completer<R> {
+    shared: Arc<Mutex<Shared<R>>>,
+}
+
+fn channel<R>() -> (HWIFuture<R>, Completer<R>) {
+    let shared = Arc::new(Mutex::new(Shared {
+        result: None,
+        waker: None,
+        done: false,
+        cancelled: false,
+    }));
+    (
+        HWIFuture {
+            shared: shared.clone(),
+        },
+        Completer { shared },
+    )
+}
+
+impl<R> Completer<R> {
+    fn is_cancelled(&self) -> bool {
+        lock(&self.shared).cancelled
+    }
+
+    fn complete(self, result: Result<R, Error>) {
+        lock(&self.shared).result = Some(result);
+    }
+}
+
+impl<R> Drop for Completer<R> {
+    fn drop(&mut self) {
+        let mut shared = lock(&self.shared);
+        shared.done = true;
+        if let Some(waker) = shared.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl<R> Future for HWIFuture<R> {
+    type Output = Result<R, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut shared = lock(&self.shared);
+        if let Some(result) = shared.result.take() {
+            return Poll::Ready(result);
+        }
+        if shared.done {
+            // The worker went away without running the call
+            return Poll::Ready(Err(Error::Hwi(
+                "the HWI worker stopped before completing the call".to_string(),
+                None,
+            )));
+        }
+        shared.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl<R> Drop for HWIFuture<R> {
+    fn drop(&mut self) {
+        lock(&self.shared).cancelled = true;
+    }
+}
+
+/// Runs `f` on a new thread, unless the returned future is dropped first.
+fn spawn<R, F>(f: F) -> HWIFuture<R>
+where
+    R: Send + 'static,
+    F: FnOnce() -> Result<R, Error> + Send + 'static,
+{
+    let (future, completer) = channel();
+    thread::spawn(move || {
+        if !completer.is_cancelled() {
+            completer.complete(f());
+        }
+    });
+    future
+}
+
+/// Async client for a single hardware wallet.
+///
+/// The wrapped [`HWIClient`] lives on a worker thread, which runs the calls one at a time, in the
+/// order they were made. The worker stops once the `AsyncHWIClient` is dropped and the pending
+/// calls are done.
+#[derive(Debug)]
+pub struct AsyncHWIClient<T: HWIImplementation + 'static> {
+    jobs: Mutex<Sender<Job<T>>>,
+}
+
+impl<T: HWIImplementation + 'static> From<HWIClient<T>> for AsyncHWIClient<T> {
+    fn from(client: HWIClient<T>) -> Self {
+        AsyncHWIClient::new(client)
+    }
+}
+
+impl<T: HWIImplementation + 'static> AsyncHWIClient<T> {
+    /// Moves `client` to a new worker thread.
+    pub fn new(client: HWIClient<T>) -> Self {
+        let (jobs, receiver) = mpsc::channel::<Job<T>>();
+        thread::spawn(move || {
+            for job in receiver {
+                job(&client);
+            }
+        });
+        AsyncHWIClient {
+            jobs: Mutex::new(jobs),
+        }
+    }
+
+    /// Connects to a device from a new worker thread, see [`HWIClient::get_client`].
+    pub fn get_client(device: HWIDevice, expert: bool, chain: HWIChain) -> HWIFuture<Self> {
+        spawn(move || HWIClient::get_client(&device, expert, chain).map(AsyncHWIClient::new))
+    }
+
+    /// Connects to a device using a BIP 39 passphrase from a new worker thread, see
+    /// [`HWIClient::get_client_with_passphrase`].
+    pub fn get_client_with_passphrase(
+        device: HWIDevice,
+        expert: bool,
+        chain: HWIChain,
+        passphrase: HWIPassphrase,
+    ) -> HWIFuture<Self> {
+        spawn(move || {
+            HWIClient::get_client_with_passphrase(&device, expert, chain, &passphrase)
+                .map(AsyncHWIClient::new)
+        })
+    }
+
+    /// Finds and connects to a device from a new worker thread, see [`HWIClient::find_device`].
+    pub fn find_device(
+        password: Option<String>,
+        device_type: Option<HWIDeviceType>,
+        fingerprint: Option<String>,
+        expert: bool,
+        chain: Network,
+    ) -> HWIFuture<Self> {
+        spawn(move || {
+            HWIClient::find_device(
+                password.as_deref(),
+                device_type,
+                fingerprint.as_deref(),
+                expert,
+                chain,
+            )
+            .map(AsyncHWIClient::new)
+        })
+    }
+
+    /// Lists all HW devices currently connected, see [`HWIClient::enumerate`].
+    pub fn enumerate() -> HWIFuture<Vec<Result<HWIDevice, Error>>> {
+        spawn(HWIClient::<T>::enumerate)
+    }
+
+    /// Lists all HW devices currently connected, sending `password` to the devices needing a
+    /// passphrase, see [`HWIClient::enumerate_with_password`].
+    pub fn enumerate_with_password(password: &str) -> HWIFuture<Vec<Result<HWIDevice, Error>>> {
+        let password = password.to_string();
+        spawn(move || HWIClient::<T>::enumerate_with_password(&password))
+    }
+
+    /// Runs `f` with the wrapped client on the worker thread.
+    ///
+    /// This gives access to the [`HWIClient`] methods without an async counterpart here.
+    pub fn run<R, F>(&self, f: F) -> HWIFuture<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&HWIClient<T>) -> Result<R, Error> + Send + 'static,
+    {
+        let (future, completer) = channel();
+        let job: Job<T> = Box::new(move |client| {
+            if !completer.is_cancelled() {
+                completer.complete(f(client));
+            }
+        });
+        // If the worker is gone, the job is dropped together with its completer, which resolves
+        // the future with an error
+        let _ = lock(&self.jobs).send(job);
+        future
+    }
+
+    /// Returns the master xpub of a device, see [`HWIClient::get_master_xpub`].
+    pub fn get_master_xpub(
+        &self,
+        addrtype: HWIAddressType,
+        account: u32,
+    ) -> HWIFuture<HWIExtendedPubKey> {
+        self.run(move |client| client.get_master_xpub(addrtype, account))
+    }
+
+    /// Signs a PSBT, see [`HWIClient::sign_tx`].
+    pub fn sign_tx(&self, psbt: Psbt) -> HWIFuture<HWIPartiallySignedTransaction> {
+        self.run(move |client| client.sign_tx(&psbt))
+    }
+
+    /// Signs a PSBT spending from a registered wallet policy, see
+    /// [`HWIClient::sign_tx_with_policy`].
+    pub fn sign_tx_with_policy(
+        &self,
+        psbt: Psbt,
+        policy: HWIWalletPolicy,
+    ) -> HWIFuture<HWIPartiallySignedTransaction> {
+        self.run(move |client| client.sign_tx_with_policy(&psbt, &policy))
+    }
+
+    /// Returns the xpub of a device, see [`HWIClient::get_xpub`].
+    pub fn get_xpub(&self, path: DerivationPath, expert: bool) -> HWIFuture<HWIExtendedPubKey> {
+        self.run(move |client| client.get_xpub(&path, expert))
+    }
+
+    /// Signs a message, see [`HWIClient::sign_message`].
+    pub fn sign_message(&self, message: String, path: DerivationPath) -> HWIFuture<HWISignature> {
+        self.run(move |client| client.sign_message(&message, &path))
+    }
+
+    /// Signs a message following BIP 322, see [`HWIClient::sign_message_bip322`].
+    pub fn sign_message_bip322(
+        &self,
+        message: String,
+        path: DerivationPath,
+        address_type: HWIAddressType,
+        format: Bip322Format,
+    ) -> HWIFuture<String> {
+        self.run(move |client| client.sign_message_bip322(&message, &path, address_type, format))
+    }
+
+    /// Returns an array of keys that can be imported in Bitcoin core using importmulti, see
+    /// [`HWIClient::get_keypool_with`].
+    pub fn get_keypool_with<D>(
+        &self,
+        request: KeypoolRequest,
+    ) -> HWIFuture<Vec<HWIKeyPoolElement<D>>>
+    where
+        D: ToDescriptor + DeserializeOwned + Send + 'static,
+    {
+        self.run(move |client| client.get_keypool_with(&request))
+    }
+
+    /// Returns device descriptors, see [`HWIClient::get_descriptors`].
+    pub fn get_descriptors<D>(&self, account: Option<u32>) -> HWIFuture<HWIDescriptor<D>>
+    where
+        D: ToDescriptor + DeserializeOwned + Send + 'static,
+    {
+        self.run(move |client| client.get_descriptors(account))
+    }
+
+    /// Returns an address given a descriptor, see [`HWIClient::display_address_with_desc`].
+    pub fn display_address_with_desc<D>(&self, descriptor: D) -> HWIFuture<HWIAddress>
+    where
+        D: ToDescriptor + ToString + Send + 'static,
+    {
+        self.run(move |client| client.display_address_with_desc(&descriptor))
+    }
+
+    /// Returns an address given a descriptor belonging to a registered wallet policy, see
+    /// [`HWIClient::display_address_with_policy`].
+    pub fn display_address_with_policy<D>(
+        &self,
+        descriptor: D,
+        policy: HWIWalletPolicy,
+    ) -> HWIFuture<HWIAddress>
+    where
+        D: ToDescriptor + ToString + Send + 'static,
+    {
+        self.run(move |client| client.display_address_with_policy(&descriptor, &policy))
+    }
+
+    /// Registers a wallet policy on the device, see [`HWIClient::register_policy`].
+    pub fn register_policy<D>(&self, name: String, descriptor: D) -> HWIFuture<HWIWalletPolicy>
+    where
+        D: ToDescriptor + ToString + Send + 'static,
+    {
+        self.run(move |client| client.register_policy(&name, &descriptor))
+    }
+
+    /// Returns an address given path and address type, see
+    /// [`HWIClient::display_address_with_path`].
+    pub fn display_address_with_path(
+        &self,
+        path: DerivationPath,
+        address_type: HWIAddressType,
+    ) -> HWIFuture<HWIAddress> {
+        self.run(move |client| client.display_address_with_path(&path, address_type))
+    }
+
+    /// Toggle whether the device is using a BIP 39 passphrase.
+    pub fn toggle_passphrase(&self) -> HWIFuture<()> {
+        self.run(|client| client.toggle_passphrase())
+    }
+
+    /// Setup a device
+    pub fn setup_device(&self, label: Option<String>, passphrase: Option<String>) -> HWIFuture<()> {
+        self.run(move |client| client.setup_device(label.as_deref(), passphrase.as_deref()))
+    }
+
+    /// Restore a device
+    pub fn restore_device(
+        &self,
+        label: Option<String>,
+        word_count: Option<HWIWordCount>,
+    ) -> HWIFuture<()> {
+        self.run(move |client| client.restore_device(label.as_deref(), word_count))
+    }
+
+    /// Create a backup of the device
+    pub fn backup_device(
+        &self,
+        label: Option<String>,
+        backup_passphrase: Option<String>,
+    ) -> HWIFuture<()> {
+        self.run(move |client| client.backup_device(label.as_deref(), backup_passphrase.as_deref()))
+    }
+
+    /// Wipe a device
+    pub fn wipe_device(&self) -> HWIFuture<()> {
+        self.run(|client| client.wipe_device())
+    }
+
+    /// Asks a locked device to show its PIN matrix, see [`HWIClient::prompt_pin`].
+    pub fn prompt_pin(&self) -> HWIFuture<()> {
+        self.run(|client| client.prompt_pin())
+    }
+
+    /// Sends the PIN of a device, see [`HWIClient::send_pin`].
+    pub fn send_pin(&self, pin: String) -> HWIFuture<()> {
+        self.run(move |client| client.send_pin(&pin))
+    }
+}
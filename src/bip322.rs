@@ -0,0 +1,337 @@
+//! [BIP 322](https://github.com/bitcoin/bips/blob/master/bip-0322.mediawiki) generic signed messages.
+//!
+//! Unlike the legacy signatures of [`sign_message`](crate::HWIClient::sign_message), BIP 322
+//! signatures can prove the ownership of segwit and taproot addresses. The message is committed to
+//! by a virtual `to_spend` transaction paying to the address, and the signature is a virtual
+//! `to_sign` transaction spending it, which devices sign like any other PSBT.
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fmt;
+
+use bitcoin::base64::{engine::general_purpose, Engine as _};
+use bitcoin::consensus::encode;
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::opcodes::all::OP_RETURN;
+use bitcoin::opcodes::OP_0;
+use bitcoin::psbt::{Input, Output};
+use bitcoin::script::{Builder, Instruction, PushBytes};
+use bitcoin::secp256k1::{Message, Secp256k1, Verification};
+use bitcoin::sighash::{Prevouts, SighashCache};
+use bitcoin::{
+    absolute, ecdsa, taproot, transaction, Address, Amount, OutPoint, Psbt, PublicKey, Script,
+    ScriptBuf, Sequence, Transaction, TxIn, TxOut, Witness, XOnlyPublicKey,
+};
+
+use crate::error::Error;
+
+const MESSAGE_TAG: &[u8] = b"BIP0322-signed-message";
+
+/// Encoding of a BIP 322 signature.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
+pub enum Bip322Format {
+    /// Only the witness of `to_sign`. Addresses needing a `scriptSig` (P2PKH and P2SH-P2WPKH)
+    /// can't be signed for in this format.
+    #[default]
+    Simple,
+    /// The whole `to_sign` transaction.
+    Full,
+}
+
+/// Errors building or decoding a BIP 322 signature.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum Bip322Error {
+    /// The address type is not supported, or not in the requested format.
+    UnsupportedAddress,
+    /// The PSBT holds no signature for the `to_sign` input.
+    MissingSignature,
+    /// The signature is not base64, or doesn't decode to a witness or a transaction.
+    InvalidEncoding,
+}
+
+impl fmt::Display for Bip322Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Bip322Error::UnsupportedAddress => f.write_str("unsupported address type"),
+            Bip322Error::MissingSignature => f.write_str("the to_sign input is not signed"),
+            Bip322Error::InvalidEncoding => f.write_str("invalid signature encoding"),
+        }
+    }
+}
+
+impl std::error::Error for Bip322Error {}
+
+/// Returns the tagged hash of `message` committed to by `to_spend`.
+pub fn message_hash(message: &str) -> sha256::Hash {
+    let tag = sha256::Hash::hash(MESSAGE_TAG);
+    let mut engine = sha256::Hash::engine();
+    engine.input(tag.as_ref());
+    engine.input(tag.as_ref());
+    engine.input(message.as_bytes());
+    sha256::Hash::from_engine(engine)
+}
+
+/// Returns the virtual `to_spend` transaction for `message`, paying to `script_pubkey`.
+pub fn to_spend(script_pubkey: &Script, message: &str) -> Transaction {
+    let script_sig = Builder::new()
+        .push_opcode(OP_0)
+        .push_slice(message_hash(message).to_byte_array())
+        .into_script();
+    Transaction {
+        version: transaction::Version(0),
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::null(),
+            script_sig,
+            sequence: Sequence::ZERO,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value: Amount::ZERO,
+            script_pubkey: script_pubkey.to_owned(),
+        }],
+    }
+}
+
+/// Returns the unsigned virtual `to_sign` transaction spending `to_spend`.
+pub fn to_sign(to_spend: &Transaction) -> Transaction {
+    Transaction {
+        version: transaction::Version(0),
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::new(to_spend.compute_txid(), 0),
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::ZERO,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value: Amount::ZERO,
+            script_pubkey: Builder::new().push_opcode(OP_RETURN).into_script(),
+        }],
+    }
+}
+
+/// Returns `to_sign` for `message` and `script_pubkey` as a PSBT, with both the witness and the
+/// non-witness UTXO of its input.
+///
+/// The key origins, and the redeem script of P2SH-P2WPKH, must still be added for the device to
+/// sign it.
+pub fn to_sign_psbt(script_pubkey: &Script, message: &str) -> Psbt {
+    let to_spend = to_spend(script_pubkey, message);
+    let input = Input {
+        witness_utxo: Some(to_spend.output[0].clone()),
+        non_witness_utxo: Some(to_spend.clone()),
+        ..Default::default()
+    };
+    Psbt {
+        unsigned_tx: to_sign(&to_spend),
+        version: 0,
+        xpub: BTreeMap::new(),
+        proprietary: BTreeMap::new(),
+        unknown: BTreeMap::new(),
+        inputs: vec![input],
+        outputs: vec![Output::default()],
+    }
+}
+
+/// Assembles the base64 signature from a `to_sign` PSBT signed by a device.
+pub fn finalize(psbt: &Psbt, format: Bip322Format) -> Result<String, Error> {
+    let input = psbt.inputs.first().ok_or(Bip322Error::MissingSignature)?;
+    let script_pubkey = match &input.witness_utxo {
+        Some(utxo) => &utxo.script_pubkey,
+        None => return Err(Bip322Error::UnsupportedAddress.into()),
+    };
+
+    let (script_sig, witness) =
+        if input.final_script_sig.is_some() || input.final_script_witness.is_some() {
+            // Already finalized by the device
+            (
+                input.final_script_sig.clone().unwrap_or_default(),
+                input.final_script_witness.clone().unwrap_or_default(),
+            )
+        } else if script_pubkey.is_p2tr() {
+            let signature = input.tap_key_sig.ok_or(Bip322Error::MissingSignature)?;
+            (ScriptBuf::new(), Witness::from_slice(&[signature.to_vec()]))
+        } else {
+            let (public_key, signature) = input
+                .partial_sigs
+                .iter()
+                .next()
+                .ok_or(Bip322Error::MissingSignature)?;
+            if script_pubkey.is_p2wpkh() {
+                (
+                    ScriptBuf::new(),
+                    Witness::p2wpkh(signature, &public_key.inner),
+                )
+            } else if script_pubkey.is_p2sh() {
+                let redeem_script = input
+                    .redeem_script
+                    .as_ref()
+                    .ok_or(Bip322Error::UnsupportedAddress)?;
+                let redeem_script: &PushBytes = redeem_script
+                    .as_bytes()
+                    .try_into()
+                    .map_err(|_| Bip322Error::UnsupportedAddress)?;
+                let script_sig = Builder::new().push_slice(redeem_script).into_script();
+                (script_sig, Witness::p2wpkh(signature, &public_key.inner))
+            } else if script_pubkey.is_p2pkh() {
+                let script_sig = Builder::new()
+                    .push_slice(signature.serialize())
+                    .push_key(public_key)
+                    .into_script();
+                (script_sig, Witness::new())
+            } else {
+                return Err(Bip322Error::UnsupportedAddress.into());
+            }
+        };
+
+    let bytes = match format {
+        Bip322Format::Simple if !script_sig.is_empty() => {
+            return Err(Bip322Error::UnsupportedAddress.into())
+        }
+        Bip322Format::Simple => encode::serialize(&witness),
+        Bip322Format::Full => {
+            let mut to_sign = psbt.unsigned_tx.clone();
+            to_sign.input[0].script_sig = script_sig;
+            to_sign.input[0].witness = witness;
+            encode::serialize(&to_sign)
+        }
+    };
+    Ok(general_purpose::STANDARD.encode(bytes))
+}
+
+/// Returns whether `signature`, in either format, is a valid BIP 322 signature of `message` by
+/// `address`.
+///
+/// P2PKH, P2SH-P2WPKH, P2WPKH and P2TR (key path) addresses are supported. Full signatures
+/// proving the ownership of additional inputs are reported as invalid.
+pub fn verify(address: &Address, message: &str, signature: &str) -> Result<bool, Error> {
+    let bytes = general_purpose::STANDARD
+        .decode(signature)
+        .map_err(|_| Bip322Error::InvalidEncoding)?;
+    let script_pubkey = address.script_pubkey();
+    if !(script_pubkey.is_p2pkh()
+        || script_pubkey.is_p2sh()
+        || script_pubkey.is_p2wpkh()
+        || script_pubkey.is_p2tr())
+    {
+        return Err(Bip322Error::UnsupportedAddress.into());
+    }
+
+    let to_spend = to_spend(&script_pubkey, message);
+    let mut to_sign = to_sign(&to_spend);
+    if let Ok(witness) = encode::deserialize::<Witness>(&bytes) {
+        to_sign.input[0].witness = witness;
+    } else if let Ok(transaction) = encode::deserialize::<Transaction>(&bytes) {
+        // The version, lock time and sequence may differ to prove a time lock
+        if transaction.input.len() != 1
+            || transaction.input[0].previous_output != to_sign.input[0].previous_output
+            || transaction.output != to_sign.output
+        {
+            return Ok(false);
+        }
+        to_sign = transaction;
+    } else {
+        return Err(Bip322Error::InvalidEncoding.into());
+    }
+
+    let secp = Secp256k1::verification_only();
+    let prevout = &to_spend.output[0];
+    Ok(verify_spend(&secp, &to_sign, prevout).is_some())
+}
+
+/// Checks the signature of the only input of `to_sign`, returning `None` if it's invalid.
+fn verify_spend<C: Verification>(
+    secp: &Secp256k1<C>,
+    to_sign: &Transaction,
+    prevout: &TxOut,
+) -> Option<()> {
+    let input = &to_sign.input[0];
+    let script_pubkey = &prevout.script_pubkey;
+    if script_pubkey.is_p2tr() {
+        if !input.script_sig.is_empty() || input.witness.len() != 1 {
+            return None;
+        }
+        let signature = taproot::Signature::from_slice(&input.witness[0]).ok()?;
+        let output_key = XOnlyPublicKey::from_slice(&script_pubkey.as_bytes()[2..]).ok()?;
+        let sighash = SighashCache::new(to_sign)
+            .taproot_key_spend_signature_hash(0, &Prevouts::All(&[prevout]), signature.sighash_type)
+            .ok()?;
+        let message = Message::from_digest(sighash.to_byte_array());
+        secp.verify_schnorr(&signature.signature, &message, &output_key)
+            .ok()
+    } else if script_pubkey.is_p2wpkh() {
+        if !input.script_sig.is_empty() {
+            return None;
+        }
+        verify_p2wpkh(secp, to_sign, script_pubkey)
+    } else if script_pubkey.is_p2sh() {
+        // Only P2SH-P2WPKH is supported, whose scriptSig is the push of the redeem script
+        let redeem_script = match pushes(&input.script_sig)?.as_slice() {
+            [redeem_script] => ScriptBuf::from_bytes(redeem_script.clone()),
+            _ => return None,
+        };
+        if !redeem_script.is_p2wpkh()
+            || &ScriptBuf::new_p2sh(&redeem_script.script_hash()) != script_pubkey
+        {
+            return None;
+        }
+        verify_p2wpkh(secp, to_sign, &redeem_script)
+    } else if script_pubkey.is_p2pkh() {
+        if !input.witness.is_empty() {
+            return None;
+        }
+        let (signature, public_key) = match pushes(&input.script_sig)?.as_slice() {
+            [signature, public_key] => (
+                ecdsa::Signature::from_slice(signature).ok()?,
+                PublicKey::from_slice(public_key).ok()?,
+            ),
+            _ => return None,
+        };
+        if &ScriptBuf::new_p2pkh(&public_key.pubkey_hash()) != script_pubkey {
+            return None;
+        }
+        let sighash = SighashCache::new(to_sign)
+            .legacy_signature_hash(0, script_pubkey, signature.sighash_type.to_u32())
+            .ok()?;
+        let message = Message::from_digest(sighash.to_byte_array());
+        secp.verify_ecdsa(&message, &signature.signature, &public_key.inner)
+            .ok()
+    } else {
+        None
+    }
+}
+
+/// Checks a P2WPKH witness spending `program`.
+fn verify_p2wpkh<C: Verification>(
+    secp: &Secp256k1<C>,
+    to_sign: &Transaction,
+    program: &Script,
+) -> Option<()> {
+    let witness = &to_sign.input[0].witness;
+    if witness.len() != 2 {
+        return None;
+    }
+    let signature = ecdsa::Signature::from_slice(&witness[0]).ok()?;
+    let public_key = PublicKey::from_slice(&witness[1]).ok()?;
+    if &ScriptBuf::new_p2wpkh(&public_key.wpubkey_hash().ok()?) != program {
+        return None;
+    }
+    let sighash = SighashCache::new(to_sign)
+        .p2wpkh_signature_hash(0, program, Amount::ZERO, signature.sighash_type)
+        .ok()?;
+    let message = Message::from_digest(sighash.to_byte_array());
+    secp.verify_ecdsa(&message, &signature.signature, &public_key.inner)
+        .ok()
+}
+
+/// Returns the data pushed by `script`, or `None` if it contains anything else.
+fn pushes(script: &Script) -> Option<Vec<Vec<u8>>> {
+    script
+        .instructions()
+        .map(|instruction| match instruction.ok()? {
+            Instruction::PushBytes(bytes) => Some(bytes.as_bytes().to_vec()),
+            Instruction::Op(_) => None,
+        })
+        .collect()
+}
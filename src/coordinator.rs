@@ -0,0 +1,157 @@
+//! Signing a PSBT with several devices.
+//!
+//! [`PsbtCoordinator`] finds the fingerprints a PSBT expects signatures from in the
+//! `bip32_derivation` and `tap_key_origins` of its inputs, matches them with the devices
+//! reported by [`HWIClient::enumerate`], and asks each connected device to sign in turn. The
+//! signed PSBTs are combined, and the [`SigningReport`] tells which keys didn't sign, e.g.
+//! because their device wasn't connected or the user rejected the transaction.
+//!
+//! A key without a signature doesn't mean the PSBT can't be finalized: a 2-of-3 multisig only
+//! needs two of its three keys. Whether the threshold of each input is met is left to the
+//! finalizer, e.g. miniscript's `PsbtExt::finalize`.
+
+use std::marker::PhantomData;
+
+use bitcoin::bip32::Fingerprint;
+use bitcoin::Psbt;
+
+use crate::error::Error;
+use crate::interface::HWIClient;
+use crate::psbt_utils::{is_finalized, unsigned_fingerprints};
+use crate::types::{HWIChain, HWIDevice, HWIImplementation, HWITimeouts};
+
+pub use crate::psbt_utils::signature_count;
+
+/// Returns the fingerprints of the keys the inputs of `psbt` can be signed with, in the order
+/// they first appear.
+pub fn required_fingerprints(psbt: &Psbt) -> Vec<Fingerprint> {
+    let mut fingerprints = Vec::new();
+    for input in &psbt.inputs {
+        let origins = input
+            .bip32_derivation
+            .values()
+            .chain(input.tap_key_origins.values().map(|(_, origin)| origin));
+        for (fingerprint, _) in origins {
+            if !fingerprints.contains(fingerprint) {
+                fingerprints.push(*fingerprint);
+            }
+        }
+    }
+    fingerprints
+}
+
+/// Returns the fingerprints of the keys that didn't sign an input of `psbt` yet. Finalized
+/// inputs are complete and never missing a signature.
+pub fn missing_fingerprints(psbt: &Psbt) -> Vec<Fingerprint> {
+    let mut fingerprints = Vec::new();
+    for input in psbt.inputs.iter().filter(|input| !is_finalized(input)) {
+        for fingerprint in unsigned_fingerprints(input) {
+            if !fingerprints.contains(&fingerprint) {
+                fingerprints.push(fingerprint);
+            }
+        }
+    }
+    fingerprints
+}
+
+/// Outcome of [`PsbtCoordinator::sign`].
+#[derive(Debug)]
+pub struct SigningReport {
+    /// The PSBT combined with the signatures of every device.
+    pub psbt: Psbt,
+    /// Fingerprints of the devices that signed every input they were needed for.
+    pub signed: Vec<Fingerprint>,
+    /// Fingerprints of the devices that failed to sign, with the error.
+    pub failed: Vec<(Fingerprint, Error)>,
+    /// Fingerprints of the keys without a signature, including the ones the threshold of a
+    /// multisig doesn't need.
+    pub missing: Vec<Fingerprint>,
+}
+
+impl SigningReport {
+    /// Returns whether every key of the PSBT signed, which is more than a multisig needs to be
+    /// finalized, see the [module documentation](self).
+    pub fn all_keys_signed(&self) -> bool {
+        self.missing.is_empty()
+    }
+}
+
+/// Signs PSBTs with every connected device they need, see the [module documentation](self).
+#[derive(Debug)]
+pub struct PsbtCoordinator<T: HWIImplementation> {
+    chain: HWIChain,
+    timeouts: HWITimeouts,
+    implementation: PhantomData<T>,
+}
+
+impl<T: HWIImplementation> PsbtCoordinator<T> {
+    /// Creates a coordinator opening the devices for `chain`.
+    pub fn new(chain: HWIChain) -> Self {
+        PsbtCoordinator {
+            chain,
+            timeouts: HWITimeouts::default(),
+            implementation: PhantomData,
+        }
+    }
+
+    /// Sets the timeouts of the clients opened for the devices.
+    pub fn timeouts(mut self, timeouts: HWITimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Signs `psbt` with the devices currently connected.
+    ///
+    /// Devices HWI can't identify, e.g. because they are locked, are ignored.
+    pub fn sign(&self, psbt: &Psbt) -> Result<SigningReport, Error> {
+        let devices: Vec<HWIDevice> = HWIClient::<T>::enumerate()?
+            .into_iter()
+            .filter_map(Result::ok)
+            .collect();
+        self.sign_with_devices(psbt, &devices)
+    }
+
+    /// Signs `psbt` with `devices`, one after the other.
+    ///
+    /// Each device signs the PSBT combined with the signatures of the previous ones. A device
+    /// failing to sign, or returning a PSBT that can't be combined, is reported in
+    /// [`SigningReport::failed`] and doesn't stop the others.
+    pub fn sign_with_devices(
+        &self,
+        psbt: &Psbt,
+        devices: &[HWIDevice],
+    ) -> Result<SigningReport, Error> {
+        let mut report = SigningReport {
+            psbt: psbt.clone(),
+            signed: Vec::new(),
+            failed: Vec::new(),
+            missing: Vec::new(),
+        };
+        for fingerprint in required_fingerprints(psbt) {
+            let device = match devices.iter().find(|d| d.fingerprint == fingerprint) {
+                Some(device) => device,
+                None => continue,
+            };
+            match self.sign_with_device(&report.psbt, device) {
+                Ok(combined) => {
+                    if !missing_fingerprints(&combined).contains(&fingerprint) {
+                        report.signed.push(fingerprint);
+                    }
+                    report.psbt = combined;
+                }
+                Err(e) => report.failed.push((fingerprint, e)),
+            }
+        }
+        report.missing = missing_fingerprints(&report.psbt);
+        Ok(report)
+    }
+
+    fn sign_with_device(&self, psbt: &Psbt, device: &HWIDevice) -> Result<Psbt, Error> {
+        let client = HWIClient::<T>::get_client(device, false, self.chain.clone())?
+            .with_timeouts(self.timeouts);
+        let signed = client.sign_tx(psbt)?.psbt;
+        let mut combined = psbt.clone();
+        combined.combine(signed)?;
+        Ok(combined)
+    }
+}
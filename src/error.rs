@@ -71,13 +71,52 @@ impl fmt::Debug for ErrorCode {
     }
 }
 
+/// Details of an exception raised by HWI.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct HWIErrorDetails {
+    pub message: String,
+    pub code: Option<ErrorCode>,
+    /// The formatted Python traceback, if any.
+    pub traceback: Option<String>,
+}
+
 #[derive(Debug)]
 pub enum Error {
     Json(serde_json::Error),
     Utf8(std::str::Utf8Error),
     Io(std::io::Error),
     Hwi(String, Option<ErrorCode>),
+    /// HWI returned, or was asked for, a malformed or unsupported value.
+    InvalidValue(String),
+    /// The device didn't answer within the timeout.
+    Timeout(std::time::Duration),
+    /// The call was cancelled through a [`HWICancelHandle`](crate::interface::HWICancelHandle).
+    Cancelled,
+    /// The user rejected the action on the device.
+    ActionCanceled(HWIErrorDetails),
+    /// The device is busy with another operation.
+    DeviceBusy(HWIErrorDetails),
+    /// The device couldn't be reached, e.g. because it was unplugged.
+    DeviceConnection(HWIErrorDetails),
+    /// The device must be unlocked or have its passphrase sent first.
+    DeviceNotReady(HWIErrorDetails),
+    /// The device has no seed yet.
+    DeviceNotInitialized(HWIErrorDetails),
+    /// The device doesn't support the action.
+    UnsupportedAction(HWIErrorDetails),
+    /// Any other exception raised by HWI.
+    HwiException(HWIErrorDetails),
+    MessageSignature(bitcoin::sign_message::MessageSignatureError),
+    Bip322(crate::bip322::Bip322Error),
+    /// The device doesn't have the expected master key fingerprint, as `(expected, actual)`.
+    FingerprintMismatch(bitcoin::bip32::Fingerprint, bitcoin::bip32::Fingerprint),
+    /// PSBTs couldn't be combined.
+    Psbt(bitcoin::psbt::Error),
+    #[cfg(feature = "python")]
     Python(pyo3::PyErr),
+    /// BDK refused the descriptors of the device.
+    #[cfg(feature = "signer")]
+    Descriptor(bdk_wallet::descriptor::DescriptorError),
 }
 
 impl fmt::Display for Error {
@@ -89,7 +128,62 @@ impl fmt::Display for Error {
             Utf8(_) => f.write_str("utf8 error"),
             Io(_) => f.write_str("I/O error"),
             Hwi(ref s, ref code) => write!(f, "HWI error: {}, ({:?})", s, code),
+            InvalidValue(ref s) => write!(f, "invalid value: {}", s),
+            Timeout(ref timeout) => write!(f, "timed out after {:?}", timeout),
+            Cancelled => f.write_str("cancelled"),
+            ActionCanceled(ref d) => write!(f, "action canceled: {}", d.message),
+            DeviceBusy(ref d) => write!(f, "device busy: {}", d.message),
+            DeviceConnection(ref d) => write!(f, "device connection error: {}", d.message),
+            DeviceNotReady(ref d) => write!(f, "device not ready: {}", d.message),
+            DeviceNotInitialized(ref d) => write!(f, "device not initialized: {}", d.message),
+            UnsupportedAction(ref d) => write!(f, "unsupported action: {}", d.message),
+            HwiException(ref d) => write!(f, "HWI error: {}, ({:?})", d.message, d.code),
+            MessageSignature(_) => f.write_str("message signature error"),
+            Bip322(_) => f.write_str("BIP 322 signature error"),
+            FingerprintMismatch(ref expected, ref actual) => write!(
+                f,
+                "expected device with fingerprint {}, found {}",
+                expected, actual
+            ),
+            Psbt(_) => f.write_str("PSBT error"),
+            #[cfg(feature = "python")]
             Python(_) => f.write_str("python error"),
+            #[cfg(feature = "signer")]
+            Descriptor(_) => f.write_str("descriptor error"),
+        }
+    }
+}
+
+impl Error {
+    /// Builds the variant matching the code of an exception raised by HWI.
+    pub fn from_details(details: HWIErrorDetails) -> Self {
+        match details.code {
+            Some(ErrorCode::ActionCanceled) => Error::ActionCanceled(details),
+            Some(ErrorCode::DeviceBusy) => Error::DeviceBusy(details),
+            Some(ErrorCode::DeviceConnError) => Error::DeviceConnection(details),
+            Some(ErrorCode::DeviceNotReady) => Error::DeviceNotReady(details),
+            Some(ErrorCode::DeviceNotInitialized) => Error::DeviceNotInitialized(details),
+            Some(ErrorCode::NotImplemented) | Some(ErrorCode::UnavailableAction) => {
+                Error::UnsupportedAction(details)
+            }
+            _ => Error::HwiException(details),
+        }
+    }
+
+    /// Returns the HWI error code, if the error comes from HWI and it provided one.
+    pub fn code(&self) -> Option<ErrorCode> {
+        use Error::*;
+
+        match *self {
+            Hwi(_, code) => code,
+            ActionCanceled(ref d)
+            | DeviceBusy(ref d)
+            | DeviceConnection(ref d)
+            | DeviceNotReady(ref d)
+            | DeviceNotInitialized(ref d)
+            | UnsupportedAction(ref d)
+            | HwiException(ref d) => d.code,
+            _ => None,
         }
     }
 }
@@ -103,7 +197,23 @@ impl std::error::Error for Error {
             Utf8(ref e) => Some(e),
             Io(ref e) => Some(e),
             Hwi(_, _) => None,
+            InvalidValue(_) => None,
+            Timeout(_) | Cancelled => None,
+            ActionCanceled(_)
+            | DeviceBusy(_)
+            | DeviceConnection(_)
+            | DeviceNotReady(_)
+            | DeviceNotInitialized(_)
+            | UnsupportedAction(_)
+            | HwiException(_) => None,
+            MessageSignature(ref e) => Some(e),
+            Bip322(ref e) => Some(e),
+            FingerprintMismatch(_, _) => None,
+            Psbt(ref e) => Some(e),
+            #[cfg(feature = "python")]
             Python(ref e) => Some(e),
+            #[cfg(feature = "signer")]
+            Descriptor(ref e) => Some(e),
         }
     }
 }
@@ -126,8 +236,64 @@ impl From<io::Error> for Error {
     }
 }
 
+impl From<crate::bip322::Bip322Error> for Error {
+    fn from(e: crate::bip322::Bip322Error) -> Self {
+        Error::Bip322(e)
+    }
+}
+
+impl From<bitcoin::psbt::Error> for Error {
+    fn from(e: bitcoin::psbt::Error) -> Self {
+        Error::Psbt(e)
+    }
+}
+
+impl From<bitcoin::sign_message::MessageSignatureError> for Error {
+    fn from(e: bitcoin::sign_message::MessageSignatureError) -> Self {
+        Error::MessageSignature(e)
+    }
+}
+
+#[cfg(feature = "signer")]
+impl From<bdk_wallet::descriptor::DescriptorError> for Error {
+    fn from(e: bdk_wallet::descriptor::DescriptorError) -> Self {
+        Error::Descriptor(e)
+    }
+}
+
+#[cfg(feature = "python")]
 impl From<pyo3::PyErr> for Error {
+    /// Exceptions raised by hwilib are mapped according to their error code, any other exception
+    /// is kept as [`Error::Python`].
     fn from(e: pyo3::PyErr) -> Self {
-        Error::Python(e)
+        use pyo3::prelude::*;
+
+        Python::with_gil(|py| {
+            let value = e.value_bound(py);
+            let is_hwi_error = py
+                .import_bound("hwilib.errors")
+                .and_then(|errors| errors.getattr("HWWError"))
+                .and_then(|class| value.is_instance(&class))
+                .unwrap_or(false);
+            if !is_hwi_error {
+                return Error::Python(e);
+            }
+
+            let message = value
+                .getattr("msg")
+                .and_then(|msg| msg.extract::<String>())
+                .unwrap_or_else(|_| value.to_string());
+            let code = value
+                .getattr("code")
+                .and_then(|code| code.extract::<i8>())
+                .ok()
+                .and_then(|code| ErrorCode::try_from(code).ok());
+            let traceback = e.traceback_bound(py).and_then(|tb| tb.format().ok());
+            Error::from_details(HWIErrorDetails {
+                message,
+                code,
+                traceback,
+            })
+        })
     }
 }
@@ -0,0 +1,40 @@
+#[cfg(feature = "binary")]
+pub mod binary_implementation;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock_implementation;
+#[cfg(feature = "python")]
+pub mod python_implementation;
+#[cfg(any(test, feature = "replay"))]
+pub mod replay_implementation;
+
+/// Error for a command, or arguments of a command, the installed HWI doesn't know, such as the
+/// wallet policies added after HWI 2.1.
+#[cfg(any(feature = "python", feature = "binary"))]
+pub(crate) fn unsupported_by_hwi(what: &str) -> crate::error::Error {
+    crate::error::Error::from_details(crate::error::HWIErrorDetails {
+        message: format!("the installed version of HWI doesn't support {}", what),
+        code: Some(crate::error::ErrorCode::NotImplemented),
+        traceback: None,
+    })
+}
+
+/// Install hwi for the current user via pip. If no version is specified, the default version from
+/// pip will be installed.
+#[cfg(any(feature = "python", feature = "binary"))]
+pub(crate) fn install_hwilib_with_pip(version: Option<&str>) -> Result<(), crate::error::Error> {
+    let hwi_with_version = match version {
+        Some(ver) => "hwi==".to_owned() + ver,
+        None => "hwi".to_owned(),
+    };
+    let output = std::process::Command::new("pip")
+        .args(vec!["install", "--user", hwi_with_version.as_str()])
+        .output()?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(crate::error::Error::Hwi(
+            String::from_utf8_lossy(&output.stderr).to_string(),
+            None,
+        ))
+    }
+}
@@ -0,0 +1,476 @@
+use std::convert::TryFrom;
+use std::ffi::OsString;
+use std::fmt::Debug;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::marker::PhantomData;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use bitcoin::bip32::DerivationPath;
+use bitcoin::hex::DisplayHex;
+use bitcoin::{Network, Psbt};
+use serde_json::value::Value;
+
+use crate::error::{Error, ErrorCode, HWIErrorDetails};
+use crate::implementations::{install_hwilib_with_pip, unsupported_by_hwi};
+use crate::types::{
+    HWIAddressType, HWIChain, HWIDevice, HWIDeviceInternal, HWIDeviceType, HWIErrorResponse,
+    HWIImplementation, HWIPassphrase, HWIWalletPolicy, KeypoolRequest, LogLevel,
+};
+
+/// Whether `--debug` should be passed to the `hwi` binary, see
+/// [`set_log_level`](HWIImplementation::set_log_level).
+static DEBUG: AtomicBool = AtomicBool::new(false);
+
+/// Environment variable overriding the path of the `hwi` executable at runtime.
+pub const HWI_BINARY_ENV: &str = "HWI_BINARY";
+
+/// Path of the `hwi` executable given the value of [`HWI_BINARY`](HWI_BINARY_ENV).
+pub(crate) fn resolve_binary_path(var: Option<OsString>) -> PathBuf {
+    var.map_or_else(|| PathBuf::from("hwi"), PathBuf::from)
+}
+
+/// Runs the `hwi` executable.
+///
+/// Implement this trait to use an executable that is not called `hwi` or is not in `PATH`, or to
+/// change how the process is spawned. The path can also be set at runtime with the
+/// [`HWI_BINARY`](HWI_BINARY_ENV) environment variable.
+pub trait HWIBinaryExecutor: Debug + Send + Sync + 'static {
+    /// Path of the `hwi` executable: the value of [`HWI_BINARY`](HWI_BINARY_ENV) if set, `hwi`
+    /// otherwise.
+    fn binary_path() -> PathBuf {
+        resolve_binary_path(std::env::var_os(HWI_BINARY_ENV))
+    }
+
+    /// Whether HWI can read the password from stdin with `--stdinpass`, otherwise it's given with
+    /// `--password`, on the command line other local users can see.
+    ///
+    /// HWI reads it with Python's `getpass`, which prompts on the terminal when there is one and
+    /// only falls back to stdin on Unix without a controlling terminal, e.g. in a service.
+    fn password_from_stdin() -> bool {
+        cfg!(unix)
+            && OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open("/dev/tty")
+                .is_err()
+    }
+
+    /// Runs the executable with `args`, writing `stdin` on its standard input, and returns what it
+    /// printed on stdout.
+    fn execute_command(args: Vec<String>, stdin: Option<&str>) -> Result<String, Error> {
+        let mut child = Command::new(Self::binary_path())
+            .args(&args)
+            .stdin(if stdin.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        if let (Some(input), Some(mut pipe)) = (stdin, child.stdin.take()) {
+            // HWI may exit without reading it, e.g. on invalid arguments, its output tells why
+            let _ = writeln!(pipe, "{}", input);
+        }
+        let output = child.wait_with_output()?;
+        let stdout = std::str::from_utf8(&output.stdout)?;
+        // On failure HWI still prints a JSON object describing the error on stdout
+        if output.status.success() || !stdout.trim().is_empty() {
+            Ok(stdout.to_string())
+        } else {
+            Err(Error::Hwi(
+                String::from_utf8_lossy(&output.stderr).trim().to_string(),
+                None,
+            ))
+        }
+    }
+}
+
+/// Executor running the `hwi` found in `PATH`, or the one set with [`HWI_BINARY`](HWI_BINARY_ENV).
+#[derive(Debug)]
+pub struct HWIBinaryExecutorDefault;
+
+impl HWIBinaryExecutor for HWIBinaryExecutorDefault {}
+
+/// HWI implementation running the standalone `hwi` executable and parsing its output.
+///
+/// This doesn't require Python to be linked into the final binary, only the `hwi` executable to
+/// be available at runtime.
+#[derive(Debug)]
+pub struct BinaryHWI<E: HWIBinaryExecutor = HWIBinaryExecutorDefault> {
+    device: HWIDevice,
+    expert: bool,
+    chain: HWIChain,
+    password: Option<String>,
+    _executor: PhantomData<E>,
+}
+
+fn chain_arg(chain: &HWIChain) -> Result<&'static str, Error> {
+    match chain.network() {
+        Network::Bitcoin => Ok("main"),
+        Network::Testnet => Ok("test"),
+        Network::Regtest => Ok("regtest"),
+        Network::Signet => Ok("signet"),
+        network => Err(Error::InvalidValue(format!(
+            "network {} is not supported by hwi",
+            network
+        ))),
+    }
+}
+
+/// Returns whether `version` looks like a version of HWI, e.g. `2.3.1` or `2.4.0rc1`.
+fn is_version(version: &str) -> bool {
+    let mut parts = version.split('.');
+    let major = parts.next().unwrap_or_default();
+    !major.is_empty()
+        && major.chars().all(|c| c.is_ascii_digit())
+        && parts.all(|part| {
+            part.starts_with(|c: char| c.is_ascii_digit())
+                && part.chars().all(|c| c.is_ascii_alphanumeric())
+        })
+}
+
+/// Arguments identifying a registered wallet policy.
+fn policy_args(policy: Option<&HWIWalletPolicy>) -> Vec<String> {
+    policy.map_or_else(Vec::new, |policy| {
+        vec![
+            "--name".to_string(),
+            policy.name.clone(),
+            "--hmac".to_string(),
+            policy.hmac.to_lower_hex_string(),
+        ]
+    })
+}
+
+/// Arguments that must precede the command name.
+///
+/// With `password_from_stdin`, see [`HWIBinaryExecutor::password_from_stdin`], the `password`
+/// must then be given to [`execute_command`](HWIBinaryExecutor::execute_command).
+pub(crate) fn global_args(
+    device: Option<&HWIDevice>,
+    password: Option<&str>,
+    password_from_stdin: bool,
+    expert: bool,
+    chain: Option<&HWIChain>,
+) -> Result<Vec<String>, Error> {
+    let mut args = Vec::new();
+    if let Some(chain) = chain {
+        args.extend(["--chain".to_string(), chain_arg(chain)?.to_string()]);
+    }
+    if let Some(device) = device {
+        args.extend([
+            "--device-type".to_string(),
+            device.device_type.to_string(),
+            "--device-path".to_string(),
+            device.path.clone(),
+        ]);
+    }
+    match password {
+        Some(_) if password_from_stdin => args.push("--stdinpass".to_string()),
+        Some(password) => args.extend(["--password".to_string(), password.to_string()]),
+        None => {}
+    }
+    if expert {
+        args.push("--expert".to_string());
+    }
+    if DEBUG.load(Ordering::Relaxed) {
+        args.push("--debug".to_string());
+    }
+    Ok(args)
+}
+
+impl<E: HWIBinaryExecutor> BinaryHWI<E> {
+    fn run_command(&self, command: &str, args: Vec<String>) -> Result<String, Error> {
+        self.run_command_with_expert(command, args, self.expert)
+    }
+
+    fn run_command_with_expert(
+        &self,
+        command: &str,
+        args: Vec<String>,
+        expert: bool,
+    ) -> Result<String, Error> {
+        let password = self.password.as_deref();
+        let from_stdin = E::password_from_stdin();
+        let mut full_args = global_args(
+            Some(&self.device),
+            password,
+            from_stdin,
+            expert,
+            Some(&self.chain),
+        )?;
+        full_args.push(command.to_string());
+        full_args.extend(args);
+        E::execute_command(full_args, password.filter(|_| from_stdin))
+    }
+
+    /// Like [`run_command`](Self::run_command), for the commands and arguments of wallet policies
+    /// older versions of HWI don't know: their argument parser rejecting them fails with
+    /// [`Error::UnsupportedAction`].
+    fn run_policy_command(&self, command: &str, args: Vec<String>) -> Result<String, Error> {
+        let output = self.run_command(command, args)?;
+        let value: Option<Value> = serde_json::from_str(&output).ok();
+        let error = value
+            .as_ref()
+            .and_then(|value| value.get("error"))
+            .and_then(Value::as_str);
+        match error {
+            Some(error) if error.contains("invalid choice") => {
+                Err(unsupported_by_hwi(&format!("the {} command", command)))
+            }
+            Some(error) if error.contains("unrecognized arguments") => Err(unsupported_by_hwi(
+                &format!("wallet policies in the {} command", command),
+            )),
+            _ => Ok(output),
+        }
+    }
+}
+
+impl<E: HWIBinaryExecutor> HWIImplementation for BinaryHWI<E> {
+    fn enumerate(password: Option<&str>) -> Result<String, Error> {
+        let from_stdin = E::password_from_stdin();
+        let mut args = global_args(None, password, from_stdin, false, None)?;
+        args.push("enumerate".to_string());
+        E::execute_command(args, password.filter(|_| from_stdin))
+    }
+
+    fn get_client(
+        device: &HWIDevice,
+        expert: bool,
+        chain: HWIChain,
+        passphrase: &HWIPassphrase,
+    ) -> Result<Self, Error> {
+        // Make sure the chain is supported before any command is run
+        chain_arg(&chain)?;
+        let password = match passphrase {
+            HWIPassphrase::Empty => None,
+            HWIPassphrase::Host(passphrase) => Some(passphrase.clone()),
+            HWIPassphrase::OnDevice => {
+                return Err(Error::from_details(HWIErrorDetails {
+                    message: "the hwi executable can't ask for the passphrase on the device"
+                        .to_string(),
+                    code: Some(ErrorCode::UnavailableAction),
+                    traceback: None,
+                }))
+            }
+        };
+        Ok(BinaryHWI {
+            device: device.clone(),
+            expert,
+            chain,
+            password,
+            _executor: PhantomData,
+        })
+    }
+
+    fn find_device(
+        password: Option<&str>,
+        device_type: Option<HWIDeviceType>,
+        fingerprint: Option<&str>,
+        expert: bool,
+        chain: Network,
+    ) -> Result<Self, Error> {
+        let chain = HWIChain::from(chain);
+        let from_stdin = E::password_from_stdin();
+        let mut args = global_args(None, password, from_stdin, false, Some(&chain))?;
+        args.push("enumerate".to_string());
+        let output = E::execute_command(args, password.filter(|_| from_stdin))?;
+        let devices: Value = serde_json::from_str(&output)?;
+        HWIErrorResponse::check(&devices)?;
+        let devices: Vec<HWIDeviceInternal> = serde_json::from_value(devices)?;
+        let device = devices
+            .into_iter()
+            .filter_map(|d| HWIDevice::try_from(d).ok())
+            .find(|d| {
+                device_type.as_ref().map_or(true, |t| &d.device_type == t)
+                    && fingerprint.map_or(true, |f| d.fingerprint.to_string() == f.to_lowercase())
+            })
+            .ok_or_else(|| Error::Hwi("device not found".to_string(), None))?;
+
+        Ok(BinaryHWI {
+            device,
+            expert,
+            chain,
+            password: password.map(String::from),
+            _executor: PhantomData,
+        })
+    }
+
+    fn chain(&self) -> HWIChain {
+        self.chain.clone()
+    }
+
+    fn get_master_xpub(&self, addrtype: HWIAddressType, account: u32) -> Result<String, Error> {
+        let args = vec![
+            "--addr-type".to_string(),
+            addrtype.to_string(),
+            "--account".to_string(),
+            account.to_string(),
+        ];
+        self.run_command("getmasterxpub", args)
+    }
+
+    fn sign_tx(&self, psbt: &Psbt, policy: Option<&HWIWalletPolicy>) -> Result<String, Error> {
+        let mut args = policy_args(policy);
+        args.push(psbt.to_string());
+        self.run_policy_command("signtx", args)
+    }
+
+    fn get_xpub(&self, path: &DerivationPath, expert: bool) -> Result<String, Error> {
+        // HWI doesn't accept a trailing slash for the master key
+        let prefixed_path = if path.is_master() {
+            "m".to_string()
+        } else {
+            format!("m/{}", path)
+        };
+        self.run_command_with_expert("getxpub", vec![prefixed_path], self.expert || expert)
+    }
+
+    fn sign_message(&self, message: &str, path: &DerivationPath) -> Result<String, Error> {
+        let prefixed_path = format!("m/{}", path);
+        self.run_command("signmessage", vec![message.to_string(), prefixed_path])
+    }
+
+    fn get_keypool(&self, request: &KeypoolRequest) -> Result<String, Error> {
+        let keypool = if request.keypool {
+            "--keypool"
+        } else {
+            "--nokeypool"
+        };
+        let mut args = vec![keypool.to_string()];
+        if request.internal {
+            args.push("--internal".to_string());
+        }
+        if request.addr_all {
+            args.push("--all".to_string());
+        } else {
+            args.extend(["--addr-type".to_string(), request.addr_type.to_string()]);
+        }
+        let account = request.account.unwrap_or(0);
+        args.extend(["--account".to_string(), account.to_string()]);
+        if let Some(p) = &request.path {
+            args.extend(["--path".to_string(), format!("m/{}/*", p)]);
+        }
+        args.extend([request.start.to_string(), request.end.to_string()]);
+        self.run_command("getkeypool", args)
+    }
+
+    fn get_descriptors(&self, account: Option<u32>) -> Result<String, Error> {
+        let args = vec!["--account".to_string(), account.unwrap_or(0).to_string()];
+        self.run_command("getdescriptors", args)
+    }
+
+    fn display_address_with_desc(
+        &self,
+        descriptor: &str,
+        policy: Option<&HWIWalletPolicy>,
+    ) -> Result<String, Error> {
+        let mut args = vec!["--desc".to_string(), descriptor.to_string()];
+        args.extend(policy_args(policy));
+        self.run_policy_command("displayaddress", args)
+    }
+
+    fn register_policy(&self, name: &str, descriptor: &str) -> Result<String, Error> {
+        let args = vec![
+            "--name".to_string(),
+            name.to_string(),
+            "--desc".to_string(),
+            descriptor.to_string(),
+        ];
+        self.run_policy_command("register", args)
+    }
+
+    fn display_address_with_path(
+        &self,
+        path: &DerivationPath,
+        address_type: HWIAddressType,
+    ) -> Result<String, Error> {
+        let args = vec![
+            "--path".to_string(),
+            format!("m/{}", path),
+            "--addr-type".to_string(),
+            address_type.to_string(),
+        ];
+        self.run_command("displayaddress", args)
+    }
+
+    fn install_udev_rules(_source: &str, location: &str) -> Result<String, Error> {
+        // The binary ships its own copy of the rules, so there's no source to choose from
+        let mut args = global_args(None, None, false, false, None)?;
+        args.extend([
+            "installudevrules".to_string(),
+            "--location".to_string(),
+            location.to_string(),
+        ]);
+        E::execute_command(args, None)
+    }
+
+    fn set_log_level(level: LogLevel) -> Result<(), Error> {
+        // The binary can only toggle debug logging
+        DEBUG.store(matches!(level, LogLevel::DEBUG), Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn toggle_passphrase(&self) -> Result<String, Error> {
+        self.run_command("togglepassphrase", vec![])
+    }
+
+    fn setup_device(&self, label: &str, passphrase: &str) -> Result<String, Error> {
+        let args = vec![
+            "--label".to_string(),
+            label.to_string(),
+            "--backup_passphrase".to_string(),
+            passphrase.to_string(),
+        ];
+        self.run_command("setup", args)
+    }
+
+    fn restore_device(&self, label: &str, word_count: u8) -> Result<String, Error> {
+        let args = vec![
+            "--word_count".to_string(),
+            word_count.to_string(),
+            "--label".to_string(),
+            label.to_string(),
+        ];
+        self.run_command("restore", args)
+    }
+
+    fn backup_device(&self, label: &str, backup_passphrase: &str) -> Result<String, Error> {
+        let args = vec![
+            "--label".to_string(),
+            label.to_string(),
+            "--backup_passphrase".to_string(),
+            backup_passphrase.to_string(),
+        ];
+        self.run_command("backup", args)
+    }
+
+    fn wipe_device(&self) -> Result<String, Error> {
+        self.run_command("wipe", vec![])
+    }
+
+    fn prompt_pin(&self) -> Result<String, Error> {
+        self.run_command("promptpin", vec![])
+    }
+
+    fn send_pin(&self, pin: &str) -> Result<String, Error> {
+        self.run_command("sendpin", vec![pin.to_string()])
+    }
+
+    fn get_version() -> Option<String> {
+        // `hwi --version` prints e.g. `hwi 2.3.1`, with the name the executable was run as,
+        // anything else means it can't be used, like hwilib failing to import for the Python
+        // implementation
+        let output = E::execute_command(vec!["--version".to_string()], None).ok()?;
+        let version = output.split_whitespace().last()?;
+        is_version(version).then(|| version.to_string())
+    }
+
+    fn install_hwilib(version: Option<&str>) -> Result<(), Error> {
+        // The hwi package installs the executable together with the library
+        install_hwilib_with_pip(version)
+    }
+}
@@ -0,0 +1,728 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+use bitcoin::bip32::{ChildNumber, DerivationPath, Fingerprint, Xpriv, Xpub};
+use bitcoin::hashes::{hmac, sha256, Hash, HashEngine};
+use bitcoin::hex::DisplayHex;
+use bitcoin::psbt::{GetKey, KeyRequest};
+use bitcoin::secp256k1::{All, Message, Secp256k1, Signing};
+use bitcoin::sign_message::{signed_msg_hash, MessageSignature};
+use bitcoin::{Address, CompressedPublicKey, Network, NetworkKind, PrivateKey, Psbt};
+
+use serde_json::{json, Value};
+
+use crate::error::{Error, ErrorCode, HWIErrorDetails};
+use crate::types::{
+    HWIAddressType, HWIChain, HWIDevice, HWIDeviceType, HWIImplementation, HWIPassphrase,
+    HWIWalletPolicy, KeypoolRequest, LogLevel,
+};
+
+/// Devices currently "plugged in", see [`MockHWI::connect`].
+static DEVICES: Mutex<Vec<Arc<Mutex<MockDevice>>>> = Mutex::new(Vec::new());
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// A programmable hardware wallet living in memory.
+///
+/// The device derives its keys from a BIP 32 master key and answers like HWI would, actually
+/// signing messages and PSBT inputs. Errors can be configured per HWI command (`enumerate`,
+/// `getmasterxpub`, `signtx`, `getxpub`, `signmessage`, `getkeypool`, `getdescriptors`,
+/// `displayaddress`, `setup`, `wipe`, `restore`, `backup`, `togglepassphrase`, `promptpin`,
+/// `sendpin`, `register`). Errors map onto the same variants as the errors of the live
+/// implementations, e.g. [`Error::ActionCanceled`] for [`ErrorCode::ActionCanceled`].
+#[derive(Clone, Debug)]
+pub struct MockDevice {
+    xprv: Xpriv,
+    fingerprint: Fingerprint,
+    device_type: HWIDeviceType,
+    model: String,
+    path: String,
+    needs_pin_sent: bool,
+    pin: Option<String>,
+    pin_prompted: bool,
+    needs_passphrase_sent: bool,
+    errors: BTreeMap<String, (String, ErrorCode)>,
+    delays: BTreeMap<String, Duration>,
+}
+
+impl MockDevice {
+    /// Creates a device from its BIP 32 master private key.
+    pub fn from_xprv(xprv: Xpriv) -> Self {
+        let fingerprint = xprv.fingerprint(&Secp256k1::signing_only());
+        MockDevice {
+            xprv,
+            fingerprint,
+            device_type: HWIDeviceType::Trezor,
+            model: "mock".to_string(),
+            path: format!("mock:{}", fingerprint),
+            needs_pin_sent: false,
+            pin: None,
+            pin_prompted: false,
+            needs_passphrase_sent: false,
+            errors: BTreeMap::new(),
+            delays: BTreeMap::new(),
+        }
+    }
+
+    /// Creates a device deriving its master key from `seed`, as per BIP 32.
+    pub fn from_seed(seed: &[u8]) -> Result<Self, Error> {
+        let xprv = Xpriv::new_master(NetworkKind::Main, seed)
+            .map_err(|e| Error::InvalidValue(format!("invalid seed: {}", e)))?;
+        Ok(MockDevice::from_xprv(xprv))
+    }
+
+    /// Overrides the fingerprint reported by the device, which otherwise is the one of the master
+    /// key.
+    pub fn with_fingerprint(mut self, fingerprint: Fingerprint) -> Self {
+        self.fingerprint = fingerprint;
+        self
+    }
+
+    /// Sets the device type and model reported by `enumerate`.
+    pub fn with_model(mut self, device_type: HWIDeviceType, model: &str) -> Self {
+        self.device_type = device_type;
+        self.model = model.to_string();
+        self
+    }
+
+    /// Sets the path reported by `enumerate`, which identifies the device.
+    pub fn with_path(mut self, path: &str) -> Self {
+        self.path = path.to_string();
+        self
+    }
+
+    /// Sets whether the device is locked and waiting for its PIN. A locked device doesn't report
+    /// its fingerprint and refuses every command.
+    pub fn with_needs_pin_sent(mut self, needs_pin_sent: bool) -> Self {
+        self.needs_pin_sent = needs_pin_sent;
+        self
+    }
+
+    /// Locks the device until `pin` is sent. As the mock has no matrix, the PIN is compared with
+    /// what is sent as is. A device locked with [`with_needs_pin_sent`](Self::with_needs_pin_sent)
+    /// accepts any PIN.
+    pub fn with_pin(mut self, pin: &str) -> Self {
+        self.needs_pin_sent = true;
+        self.pin = Some(pin.to_string());
+        self
+    }
+
+    /// Sets whether the device is waiting for its passphrase. Such a device refuses the commands
+    /// of clients opened without a passphrase.
+    pub fn with_needs_passphrase_sent(mut self, needs_passphrase_sent: bool) -> Self {
+        self.needs_passphrase_sent = needs_passphrase_sent;
+        self
+    }
+
+    /// Makes `command` fail with `message` and `code`.
+    pub fn with_error(mut self, command: &str, code: ErrorCode, message: &str) -> Self {
+        self.errors
+            .insert(command.to_string(), (message.to_string(), code));
+        self
+    }
+
+    /// Makes `command` take `delay` to answer, as if waiting for the user.
+    pub fn with_delay(mut self, command: &str, delay: Duration) -> Self {
+        self.delays.insert(command.to_string(), delay);
+        self
+    }
+
+    /// Returns the fingerprint reported by the device.
+    pub fn fingerprint(&self) -> Fingerprint {
+        self.fingerprint
+    }
+
+    /// Returns the path identifying the device.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// HMAC proving the policy `name` with `descriptor` was registered on this device.
+    fn policy_hmac(&self, name: &str, descriptor: &str) -> Vec<u8> {
+        let mut engine =
+            hmac::HmacEngine::<sha256::Hash>::new(&self.xprv.private_key.secret_bytes());
+        engine.input(name.as_bytes());
+        engine.input(&[0]);
+        engine.input(descriptor.as_bytes());
+        hmac::Hmac::from_engine(engine).to_byte_array().to_vec()
+    }
+
+    /// Fails unless `policy` was registered on this device.
+    fn check_policy(&self, policy: Option<&HWIWalletPolicy>) -> Result<(), Error> {
+        match policy {
+            Some(policy) if self.policy_hmac(&policy.name, &policy.descriptor) != policy.hmac => {
+                Err(bad_argument("Invalid HMAC for the wallet policy"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the device as HWI enumerates it, which needs the passphrase to be sent to get the
+    /// fingerprint of a device with `needs_passphrase_sent`.
+    fn enumerate_entry(&self, passphrase_sent: bool) -> Value {
+        let mut entry = json!({
+            "type": self.device_type.to_string(),
+            "model": self.model,
+            "path": self.path,
+            "needs_pin_sent": self.needs_pin_sent,
+            "needs_passphrase_sent": self.needs_passphrase_sent,
+        });
+        let error = match self.errors.get("enumerate") {
+            Some((message, code)) => Some((message.as_str(), *code)),
+            None if self.needs_pin_sent => Some((
+                "Could not open client or get fingerprint information: device is locked",
+                ErrorCode::DeviceNotReady,
+            )),
+            None if self.needs_passphrase_sent && !passphrase_sent => Some((
+                "Passphrase needs to be specified before the fingerprint information can be retrieved",
+                ErrorCode::DeviceNotReady,
+            )),
+            None => None,
+        };
+        match error {
+            Some((message, code)) => {
+                entry["error"] = json!(message);
+                entry["code"] = json!(code.as_i8());
+            }
+            None => entry["fingerprint"] = json!(self.fingerprint.to_string()),
+        }
+        entry
+    }
+
+    /// Fails if the device can't run `command`.
+    fn check(&self, command: &str) -> Result<(), Error> {
+        if let Some((message, code)) = self.errors.get(command) {
+            return Err(hwi_error(message.clone(), *code));
+        }
+        if self.needs_pin_sent {
+            return Err(hwi_error(
+                "device is locked".to_string(),
+                ErrorCode::DeviceNotReady,
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Resolves the key requests of [`Psbt::sign`] against the device keys.
+struct MockKeys<'a> {
+    xprv: &'a Xpriv,
+    fingerprint: Fingerprint,
+}
+
+impl GetKey for MockKeys<'_> {
+    type Error = bitcoin::bip32::Error;
+
+    fn get_key<C: Signing>(
+        &self,
+        key_request: KeyRequest,
+        secp: &Secp256k1<C>,
+    ) -> Result<Option<PrivateKey>, Self::Error> {
+        match key_request {
+            KeyRequest::Bip32((fingerprint, path)) if fingerprint == self.fingerprint => {
+                Ok(Some(self.xprv.derive_priv(secp, &path)?.to_priv()))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+/// HWI implementation backed by [`MockDevice`]s, for testing code using [`HWIClient`] without
+/// any hardware or emulator.
+///
+/// Devices are registered process-wide with [`connect`](MockHWI::connect), after which they are
+/// returned by `enumerate` and can be opened with `get_client` and `find_device`. The clients of
+/// a device fail with `DeviceConnError` once it's unplugged.
+///
+/// [`HWIClient`]: crate::HWIClient
+#[derive(Debug)]
+pub struct MockHWI {
+    device: Arc<Mutex<MockDevice>>,
+    chain: HWIChain,
+    passphrase_sent: bool,
+    secp: Secp256k1<All>,
+}
+
+impl MockHWI {
+    /// Plugs `device` in, replacing any device with the same path.
+    pub fn connect(device: MockDevice) {
+        let mut devices = lock(&DEVICES);
+        devices.retain(|d| lock(d).path != device.path);
+        devices.push(Arc::new(Mutex::new(device)));
+    }
+
+    /// Unplugs the device with the given path.
+    pub fn disconnect(path: &str) {
+        lock(&DEVICES).retain(|d| lock(d).path != path);
+    }
+
+    /// Unplugs all devices.
+    pub fn disconnect_all() {
+        lock(&DEVICES).clear();
+    }
+
+    /// Unplugs all devices but `device` and returns it as enumerated, the fixture of the tests.
+    #[cfg(test)]
+    pub(crate) fn connect_only(device: MockDevice) -> HWIDevice {
+        Self::disconnect_all();
+        Self::connect(device);
+        crate::HWIClient::<MockHWI>::enumerate()
+            .unwrap()
+            .remove(0)
+            .unwrap()
+    }
+
+    fn new(device: Arc<Mutex<MockDevice>>, chain: HWIChain, passphrase_sent: bool) -> Self {
+        MockHWI {
+            device,
+            chain,
+            passphrase_sent,
+            secp: Secp256k1::new(),
+        }
+    }
+
+    /// Returns the device state after checking it can run `command`.
+    fn device(&self, command: &str) -> Result<MutexGuard<'_, MockDevice>, Error> {
+        let delay = lock(&self.device).delays.get(command).copied();
+        if let Some(delay) = delay {
+            thread::sleep(delay);
+        }
+        if !lock(&DEVICES).iter().any(|d| Arc::ptr_eq(d, &self.device)) {
+            return Err(hwi_error(
+                "device disconnected".to_string(),
+                ErrorCode::DeviceConnError,
+            ));
+        }
+        let device = lock(&self.device);
+        device.check(command)?;
+        if device.needs_passphrase_sent && !self.passphrase_sent {
+            return Err(hwi_error(
+                "the passphrase needs to be sent to the device".to_string(),
+                ErrorCode::DeviceNotReady,
+            ));
+        }
+        Ok(device)
+    }
+
+    fn coin_type(&self) -> u32 {
+        match self.chain.network() {
+            Network::Bitcoin => 0,
+            _ => 1,
+        }
+    }
+
+    fn account_path(
+        &self,
+        addr_type: &HWIAddressType,
+        account: u32,
+    ) -> Result<DerivationPath, Error> {
+        let purpose = match addr_type {
+            HWIAddressType::Legacy => 44,
+            HWIAddressType::Sh_Wit => 49,
+            HWIAddressType::Wit => 84,
+            HWIAddressType::Tap => 86,
+        };
+        [purpose, self.coin_type(), account]
+            .iter()
+            .map(|&index| ChildNumber::from_hardened_idx(index).map_err(bad_argument))
+            .collect()
+    }
+
+    fn derive(&self, device: &MockDevice, path: &DerivationPath) -> Result<Xpriv, Error> {
+        device
+            .xprv
+            .derive_priv(&self.secp, path)
+            .map_err(bad_argument)
+    }
+
+    fn xpub(&self, device: &MockDevice, path: &DerivationPath) -> Result<Xpub, Error> {
+        let mut xpub = Xpub::from_priv(&self.secp, &self.derive(device, path)?);
+        xpub.network = self.chain.network().into();
+        Ok(xpub)
+    }
+
+    fn address(
+        &self,
+        device: &MockDevice,
+        path: &DerivationPath,
+        address_type: &HWIAddressType,
+    ) -> Result<Address, Error> {
+        let public_key = CompressedPublicKey(
+            self.derive(device, path)?
+                .private_key
+                .public_key(&self.secp),
+        );
+        let network = self.chain.network();
+        Ok(match address_type {
+            HWIAddressType::Legacy => Address::p2pkh(public_key, network),
+            HWIAddressType::Sh_Wit => Address::p2shwpkh(&public_key, network),
+            HWIAddressType::Wit => Address::p2wpkh(&public_key, network),
+            HWIAddressType::Tap => Address::p2tr(
+                &self.secp,
+                public_key.0.x_only_public_key().0,
+                None,
+                network,
+            ),
+        })
+    }
+
+    /// Returns the descriptor of `addr_type` for the keys derived from `path`, followed by
+    /// `suffix`, as HWI would.
+    fn descriptor(
+        &self,
+        device: &MockDevice,
+        addr_type: &HWIAddressType,
+        path: &DerivationPath,
+        suffix: &str,
+    ) -> Result<String, Error> {
+        let key = format!(
+            "[{}/{}]{}{}",
+            device.fingerprint,
+            path,
+            self.xpub(device, path)?,
+            suffix
+        );
+        let descriptor = match addr_type {
+            HWIAddressType::Legacy => format!("pkh({})", key),
+            HWIAddressType::Sh_Wit => format!("sh(wpkh({}))", key),
+            HWIAddressType::Wit => format!("wpkh({})", key),
+            HWIAddressType::Tap => format!("tr({})", key),
+        };
+        let checksum = descriptor_checksum(&descriptor)?;
+        Ok(format!("{}#{}", descriptor, checksum))
+    }
+
+    fn errors(&self, command: &str) -> Result<MutexGuard<'_, MockDevice>, Error> {
+        let device = lock(&self.device);
+        if let Some((message, code)) = device.errors.get(command) {
+            return Err(hwi_error(message.clone(), *code));
+        }
+        Ok(device)
+    }
+
+    fn success(&self, command: &str) -> Result<String, Error> {
+        lock(&self.device).check(command)?;
+        Ok(json!({ "success": true }).to_string())
+    }
+}
+
+/// Builds the error HWI reports for `code`, as the live implementations do.
+fn hwi_error(message: impl Into<String>, code: ErrorCode) -> Error {
+    Error::from_details(HWIErrorDetails {
+        message: message.into(),
+        code: Some(code),
+        traceback: None,
+    })
+}
+
+fn bad_argument(e: impl std::fmt::Display) -> Error {
+    hwi_error(e.to_string(), ErrorCode::BadArgument)
+}
+
+const ADDRESS_TYPES: [HWIAddressType; 4] = [
+    HWIAddressType::Legacy,
+    HWIAddressType::Sh_Wit,
+    HWIAddressType::Wit,
+    HWIAddressType::Tap,
+];
+
+/// Computes the checksum of a descriptor, as defined in BIP 380.
+pub(crate) fn descriptor_checksum(descriptor: &str) -> Result<String, Error> {
+    const INPUT_CHARSET: &str = "0123456789()[],'/*abcdefgh@:$%{}IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+    const CHECKSUM_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+    fn poly_mod(mut c: u64, val: u64) -> u64 {
+        let c0 = c >> 35;
+        c = ((c & 0x7ffffffff) << 5) ^ val;
+        if c0 & 1 > 0 {
+            c ^= 0xf5dee51989
+        };
+        if c0 & 2 > 0 {
+            c ^= 0xa9fdca3312
+        };
+        if c0 & 4 > 0 {
+            c ^= 0x1bab10e32d
+        };
+        if c0 & 8 > 0 {
+            c ^= 0x3706b1677a
+        };
+        if c0 & 16 > 0 {
+            c ^= 0x644d626ffd
+        };
+        c
+    }
+
+    let mut c = 1;
+    let mut cls = 0;
+    let mut clscount = 0;
+    for ch in descriptor.chars() {
+        let pos = INPUT_CHARSET
+            .find(ch)
+            .ok_or_else(|| bad_argument(format!("invalid descriptor character {}", ch)))?
+            as u64;
+        c = poly_mod(c, pos & 31);
+        cls = cls * 3 + (pos >> 5);
+        clscount += 1;
+        if clscount == 3 {
+            c = poly_mod(c, cls);
+            cls = 0;
+            clscount = 0;
+        }
+    }
+    if clscount > 0 {
+        c = poly_mod(c, cls);
+    }
+    for _ in 0..8 {
+        c = poly_mod(c, 0);
+    }
+    c ^= 1;
+
+    Ok((0..8)
+        .map(|j| CHECKSUM_CHARSET[((c >> (5 * (7 - j))) & 31) as usize] as char)
+        .collect())
+}
+
+impl HWIImplementation for MockHWI {
+    fn enumerate(password: Option<&str>) -> Result<String, Error> {
+        let devices: Vec<Value> = lock(&DEVICES)
+            .iter()
+            .map(|d| lock(d).enumerate_entry(password.is_some()))
+            .collect();
+        Ok(Value::Array(devices).to_string())
+    }
+
+    fn get_client(
+        device: &HWIDevice,
+        _expert: bool,
+        chain: HWIChain,
+        passphrase: &HWIPassphrase,
+    ) -> Result<Self, Error> {
+        let device = lock(&DEVICES)
+            .iter()
+            .find(|d| lock(d).path == device.path)
+            .cloned()
+            .ok_or_else(|| {
+                hwi_error(
+                    format!("no device at path {}", device.path),
+                    ErrorCode::DeviceConnError,
+                )
+            })?;
+        let passphrase_sent = *passphrase != HWIPassphrase::Empty;
+        Ok(MockHWI::new(device, chain, passphrase_sent))
+    }
+
+    fn find_device(
+        password: Option<&str>,
+        device_type: Option<HWIDeviceType>,
+        fingerprint: Option<&str>,
+        _expert: bool,
+        chain: Network,
+    ) -> Result<Self, Error> {
+        let device = lock(&DEVICES)
+            .iter()
+            .find(|d| {
+                let d = lock(d);
+                !d.needs_pin_sent
+                    && device_type.as_ref().map_or(true, |t| &d.device_type == t)
+                    && fingerprint.map_or(true, |f| d.fingerprint.to_string() == f.to_lowercase())
+            })
+            .cloned()
+            .ok_or_else(|| Error::Hwi("device not found".to_string(), None))?;
+        Ok(MockHWI::new(device, chain.into(), password.is_some()))
+    }
+
+    fn chain(&self) -> HWIChain {
+        self.chain.clone()
+    }
+
+    fn get_master_xpub(&self, addrtype: HWIAddressType, account: u32) -> Result<String, Error> {
+        let device = self.device("getmasterxpub")?;
+        let path = self.account_path(&addrtype, account)?;
+        Ok(json!({ "xpub": self.xpub(&device, &path)?.to_string() }).to_string())
+    }
+
+    fn sign_tx(&self, psbt: &Psbt, policy: Option<&HWIWalletPolicy>) -> Result<String, Error> {
+        let device = self.device("signtx")?;
+        device.check_policy(policy)?;
+        let keys = MockKeys {
+            xprv: &device.xprv,
+            fingerprint: device.fingerprint,
+        };
+        let mut psbt = psbt.clone();
+        let signed = match psbt.sign(&keys, &self.secp) {
+            Ok(used) => used.values().any(|keys| match keys {
+                bitcoin::psbt::SigningKeys::Ecdsa(keys) => !keys.is_empty(),
+                bitcoin::psbt::SigningKeys::Schnorr(keys) => !keys.is_empty(),
+            }),
+            Err((_, errors)) => {
+                let message = errors
+                    .iter()
+                    .map(|(index, e)| format!("input {}: {}", index, e))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                return Err(hwi_error(message, ErrorCode::InvalidTx));
+            }
+        };
+        Ok(json!({ "psbt": psbt.to_string(), "signed": signed }).to_string())
+    }
+
+    fn get_xpub(&self, path: &DerivationPath, _expert: bool) -> Result<String, Error> {
+        let device = self.device("getxpub")?;
+        Ok(json!({ "xpub": self.xpub(&device, path)?.to_string() }).to_string())
+    }
+
+    fn sign_message(&self, message: &str, path: &DerivationPath) -> Result<String, Error> {
+        let device = self.device("signmessage")?;
+        let private_key = self.derive(&device, path)?.private_key;
+        let msg = Message::from_digest(signed_msg_hash(message).to_byte_array());
+        let signature = self.secp.sign_ecdsa_recoverable(&msg, &private_key);
+        let signature = MessageSignature::new(signature, true);
+        Ok(json!({ "signature": signature.to_base64() }).to_string())
+    }
+
+    fn get_keypool(&self, request: &KeypoolRequest) -> Result<String, Error> {
+        let device = self.device("getkeypool")?;
+        let addr_types = if request.addr_all {
+            ADDRESS_TYPES.to_vec()
+        } else {
+            vec![request.addr_type.clone()]
+        };
+        let change = if request.internal { "/1/*" } else { "/0/*" };
+        let elements = addr_types
+            .iter()
+            .map(|addr_type| {
+                let desc = match &request.path {
+                    Some(path) => self.descriptor(&device, addr_type, path, "/*")?,
+                    None => {
+                        let path = self.account_path(addr_type, request.account.unwrap_or(0))?;
+                        self.descriptor(&device, addr_type, &path, change)?
+                    }
+                };
+                Ok(json!({
+                    "desc": desc,
+                    "range": [request.start, request.end],
+                    "timestamp": "now",
+                    "internal": request.internal,
+                    "keypool": request.keypool,
+                    "watchonly": true,
+                }))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        Ok(Value::Array(elements).to_string())
+    }
+
+    fn get_descriptors(&self, account: Option<u32>) -> Result<String, Error> {
+        let device = self.device("getdescriptors")?;
+        let mut receive = Vec::new();
+        let mut internal = Vec::new();
+        for addr_type in ADDRESS_TYPES.iter() {
+            let path = self.account_path(addr_type, account.unwrap_or(0))?;
+            receive.push(self.descriptor(&device, addr_type, &path, "/0/*")?);
+            internal.push(self.descriptor(&device, addr_type, &path, "/1/*")?);
+        }
+        Ok(json!({ "receive": receive, "internal": internal }).to_string())
+    }
+
+    fn display_address_with_desc(
+        &self,
+        _descriptor: &str,
+        policy: Option<&HWIWalletPolicy>,
+    ) -> Result<String, Error> {
+        self.device("displayaddress")?.check_policy(policy)?;
+        Err(hwi_error(
+            "displaying addresses from descriptors is not supported by the mock".to_string(),
+            ErrorCode::UnavailableAction,
+        ))
+    }
+
+    fn register_policy(&self, name: &str, descriptor: &str) -> Result<String, Error> {
+        let device = self.device("register")?;
+        if name.is_empty() {
+            return Err(bad_argument("The wallet policy name can't be empty"));
+        }
+        let hmac = device.policy_hmac(name, descriptor);
+        Ok(json!({ "hmac": hmac.to_lower_hex_string() }).to_string())
+    }
+
+    fn display_address_with_path(
+        &self,
+        path: &DerivationPath,
+        address_type: HWIAddressType,
+    ) -> Result<String, Error> {
+        let device = self.device("displayaddress")?;
+        let address = self.address(&device, path, &address_type)?;
+        Ok(json!({ "address": address.to_string() }).to_string())
+    }
+
+    fn install_udev_rules(_source: &str, _location: &str) -> Result<String, Error> {
+        Ok(json!({ "success": true }).to_string())
+    }
+
+    fn set_log_level(_level: LogLevel) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn toggle_passphrase(&self) -> Result<String, Error> {
+        self.success("togglepassphrase")
+    }
+
+    fn setup_device(&self, _label: &str, _passphrase: &str) -> Result<String, Error> {
+        self.success("setup")
+    }
+
+    fn restore_device(&self, _label: &str, _word_count: u8) -> Result<String, Error> {
+        self.success("restore")
+    }
+
+    fn backup_device(&self, _label: &str, _backup_passphrase: &str) -> Result<String, Error> {
+        self.success("backup")
+    }
+
+    fn wipe_device(&self) -> Result<String, Error> {
+        self.success("wipe")
+    }
+
+    fn prompt_pin(&self) -> Result<String, Error> {
+        let mut device = self.errors("promptpin")?;
+        if !device.needs_pin_sent {
+            return Err(hwi_error(
+                "The PIN has already been sent to this device".to_string(),
+                ErrorCode::DeviceAlreadyUnlocked,
+            ));
+        }
+        device.pin_prompted = true;
+        Ok(json!({ "success": true }).to_string())
+    }
+
+    fn send_pin(&self, pin: &str) -> Result<String, Error> {
+        let mut device = self.errors("sendpin")?;
+        if !device.needs_pin_sent {
+            return Err(hwi_error(
+                "The PIN has already been sent to this device".to_string(),
+                ErrorCode::DeviceAlreadyUnlocked,
+            ));
+        }
+        if pin.is_empty() || !pin.chars().all(|c| ('1'..='9').contains(&c)) {
+            return Err(bad_argument("Non-numeric PIN provided"));
+        }
+        let unlocked = device.pin_prompted && device.pin.as_ref().map_or(true, |p| p == pin);
+        device.pin_prompted = false;
+        device.needs_pin_sent = !unlocked;
+        Ok(json!({ "success": unlocked }).to_string())
+    }
+
+    fn get_version() -> Option<String> {
+        Some(env!("CARGO_PKG_VERSION").to_string())
+    }
+
+    fn install_hwilib(_version: Option<&str>) -> Result<(), Error> {
+        Err(hwi_error(
+            "the mock implementation doesn't use hwilib".to_string(),
+            ErrorCode::NotImplemented,
+        ))
+    }
+}
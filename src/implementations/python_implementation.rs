@@ -0,0 +1,344 @@
+use std::ops::Deref;
+
+use bitcoin::bip32::DerivationPath;
+use bitcoin::hex::DisplayHex;
+use bitcoin::Psbt;
+
+use crate::error::Error;
+use crate::implementations::{install_hwilib_with_pip, unsupported_by_hwi};
+use crate::types::{
+    HWIAddressType, HWIChain, HWIDevice, HWIDeviceType, HWIImplementation, HWIPassphrase,
+    HWIWalletPolicy, KeypoolRequest, LogLevel,
+};
+
+use pyo3::types::{PyDict, PyTuple};
+use pyo3::{prelude::*, py_run};
+
+/// Convenience class containing required Python objects
+#[derive(Debug)]
+struct HWILib {
+    commands: Py<PyModule>,
+    json_dumps: Py<PyAny>,
+}
+
+impl HWILib {
+    pub fn initialize() -> Result<Self, Error> {
+        Python::with_gil(|py| {
+            let commands: Py<PyModule> = PyModule::import_bound(py, "hwilib.commands")?.into();
+            let json_dumps: Py<PyAny> =
+                PyModule::import_bound(py, "json")?.getattr("dumps")?.into();
+            Ok(HWILib {
+                commands,
+                json_dumps,
+            })
+        })
+    }
+}
+
+/// HWI implementation calling `hwilib` through an embedded Python interpreter (PyO3).
+#[derive(Debug)]
+pub struct PythonHWI {
+    hwilib: HWILib,
+    hw_client: PyObject,
+    chain: HWIChain,
+}
+
+impl Deref for PythonHWI {
+    type Target = PyObject;
+
+    fn deref(&self) -> &Self::Target {
+        &self.hw_client
+    }
+}
+
+impl PythonHWI {
+    /// Calls `hwilib.commands.<command>` with `args` and returns the result serialized as JSON.
+    fn call_command(&self, command: &str, args: impl IntoPy<Py<PyTuple>>) -> Result<String, Error> {
+        self.call_command_with_policy(command, args, None)
+    }
+
+    /// Like [`call_command`](Self::call_command), also passing the name and HMAC of `policy`
+    /// as keyword arguments.
+    ///
+    /// Fails with [`Error::UnsupportedAction`] if the installed `hwilib` doesn't have the
+    /// command, or doesn't take a policy for it.
+    fn call_command_with_policy(
+        &self,
+        command: &str,
+        args: impl IntoPy<Py<PyTuple>>,
+        policy: Option<&HWIWalletPolicy>,
+    ) -> Result<String, Error> {
+        Python::with_gil(|py| {
+            let commands = self.hwilib.commands.bind(py);
+            if !commands.hasattr(command)? {
+                return Err(unsupported_by_hwi(&format!("the {} command", command)));
+            }
+            let function = commands.getattr(command)?;
+            let kwargs = PyDict::new_bound(py);
+            if let Some(policy) = policy {
+                let parameters = PyModule::import_bound(py, "inspect")?
+                    .getattr("signature")?
+                    .call1((&function,))?
+                    .getattr("parameters")?;
+                if !parameters.contains("name")? || !parameters.contains("hmac")? {
+                    return Err(unsupported_by_hwi(&format!(
+                        "wallet policies in the {} command",
+                        command
+                    )));
+                }
+                kwargs.set_item("name", &policy.name)?;
+                kwargs.set_item("hmac", policy.hmac.to_lower_hex_string())?;
+            }
+            let output = function.call(args, Some(&kwargs))?;
+            let output = self.hwilib.json_dumps.call1(py, (output,))?;
+            Ok(output.to_string())
+        })
+    }
+}
+
+impl HWIImplementation for PythonHWI {
+    fn enumerate(password: Option<&str>) -> Result<String, Error> {
+        let libs = HWILib::initialize()?;
+        Python::with_gil(|py| {
+            let output = libs
+                .commands
+                .getattr(py, "enumerate")?
+                .call1(py, (password,))?;
+            let output = libs.json_dumps.call1(py, (output,))?;
+            Ok(output.to_string())
+        })
+    }
+
+    fn get_client(
+        device: &HWIDevice,
+        expert: bool,
+        chain: HWIChain,
+        passphrase: &HWIPassphrase,
+    ) -> Result<Self, Error> {
+        let libs = HWILib::initialize()?;
+        Python::with_gil(|py| {
+            // hwilib asks for the passphrase on the device when it gets None
+            let password = match passphrase {
+                HWIPassphrase::Empty => Some(""),
+                HWIPassphrase::Host(passphrase) => Some(passphrase.as_str()),
+                HWIPassphrase::OnDevice => None,
+            };
+            let client_args = (
+                device.device_type.to_string(),
+                &device.path,
+                password,
+                expert,
+                chain.to_py(py)?,
+            );
+            let client = libs
+                .commands
+                .getattr(py, "get_client")?
+                .call1(py, client_args)?;
+            Ok(PythonHWI {
+                hwilib: libs,
+                hw_client: client,
+                chain,
+            })
+        })
+    }
+
+    fn find_device(
+        password: Option<&str>,
+        device_type: Option<HWIDeviceType>,
+        fingerprint: Option<&str>,
+        expert: bool,
+        chain: bitcoin::Network,
+    ) -> Result<Self, Error> {
+        let libs = HWILib::initialize()?;
+        Python::with_gil(|py| {
+            let client_args = (
+                password.unwrap_or(""),
+                device_type.map_or_else(String::new, |d| d.to_string()),
+                fingerprint.unwrap_or(""),
+                expert,
+                HWIChain::from(chain).to_py(py)?,
+            );
+            let client = libs
+                .commands
+                .getattr(py, "find_device")?
+                .call1(py, client_args)?;
+
+            if client.is_none(py) {
+                return Err(Error::Hwi("device not found".to_string(), None));
+            }
+
+            Ok(PythonHWI {
+                hwilib: libs,
+                hw_client: client,
+                chain: chain.into(),
+            })
+        })
+    }
+
+    fn chain(&self) -> HWIChain {
+        self.chain.clone()
+    }
+
+    fn get_master_xpub(&self, addrtype: HWIAddressType, account: u32) -> Result<String, Error> {
+        Python::with_gil(|py| {
+            let func_args = (&self.hw_client, addrtype.to_py(py)?, account);
+            self.call_command("getmasterxpub", func_args)
+        })
+    }
+
+    fn sign_tx(&self, psbt: &Psbt, policy: Option<&HWIWalletPolicy>) -> Result<String, Error> {
+        let func_args = (&self.hw_client, psbt.to_string());
+        self.call_command_with_policy("signtx", func_args, policy)
+    }
+
+    fn get_xpub(&self, path: &DerivationPath, expert: bool) -> Result<String, Error> {
+        // HWI doesn't accept a trailing slash for the master key
+        let prefixed_path = if path.is_master() {
+            "m".to_string()
+        } else {
+            format!("m/{}", path)
+        };
+        self.call_command("getxpub", (&self.hw_client, prefixed_path, expert))
+    }
+
+    fn sign_message(&self, message: &str, path: &DerivationPath) -> Result<String, Error> {
+        let prefixed_path = format!("m/{}", path);
+        self.call_command("signmessage", (&self.hw_client, message, prefixed_path))
+    }
+
+    fn get_keypool(&self, request: &KeypoolRequest) -> Result<String, Error> {
+        Python::with_gil(|py| {
+            let mut p_str = py.None();
+            if let Some(p) = &request.path {
+                p_str = format!("m/{}/*", p).into_py(py);
+            }
+            let func_args = (
+                &self.hw_client,
+                p_str,
+                request.start,
+                request.end,
+                request.internal,
+                request.keypool,
+                request.account.unwrap_or(0),
+                request.addr_type.to_py(py)?,
+                request.addr_all,
+            );
+            self.call_command("getkeypool", func_args)
+        })
+    }
+
+    fn get_descriptors(&self, account: Option<u32>) -> Result<String, Error> {
+        self.call_command("getdescriptors", (&self.hw_client, account.unwrap_or(0)))
+    }
+
+    fn display_address_with_desc(
+        &self,
+        descriptor: &str,
+        policy: Option<&HWIWalletPolicy>,
+    ) -> Result<String, Error> {
+        Python::with_gil(|py| {
+            let path = py.None();
+            let func_args = (&self.hw_client, path, descriptor);
+            self.call_command_with_policy("displayaddress", func_args, policy)
+        })
+    }
+
+    fn register_policy(&self, name: &str, descriptor: &str) -> Result<String, Error> {
+        self.call_command("register", (&self.hw_client, name, descriptor))
+    }
+
+    fn display_address_with_path(
+        &self,
+        path: &DerivationPath,
+        address_type: HWIAddressType,
+    ) -> Result<String, Error> {
+        Python::with_gil(|py| {
+            let prefixed_path = format!("m/{}", path);
+            let descriptor = py.None();
+            let func_args = (
+                &self.hw_client,
+                prefixed_path,
+                descriptor,
+                address_type.to_py(py)?,
+            );
+            self.call_command("displayaddress", func_args)
+        })
+    }
+
+    fn install_udev_rules(source: &str, location: &str) -> Result<String, Error> {
+        let libs = HWILib::initialize()?;
+        Python::with_gil(|py| {
+            let output = libs
+                .commands
+                .getattr(py, "install_udev_rules")?
+                .call1(py, (source, location))?;
+            let output = libs.json_dumps.call1(py, (output,))?;
+            Ok(output.to_string())
+        })
+    }
+
+    fn set_log_level(level: LogLevel) -> Result<(), Error> {
+        Python::with_gil(|py| {
+            let arg = match level {
+                LogLevel::DEBUG => 10,
+                LogLevel::INFO => 20,
+                LogLevel::WARNING => 30,
+                LogLevel::ERROR => 40,
+                LogLevel::CRITICAL => 50,
+            };
+            py_run!(
+                py,
+                arg,
+                r#"
+                import logging
+                logging.basicConfig(level=arg)            
+                "#
+            );
+            Ok(())
+        })
+    }
+
+    fn toggle_passphrase(&self) -> Result<String, Error> {
+        self.call_command("toggle_passphrase", (&self.hw_client,))
+    }
+
+    fn setup_device(&self, label: &str, passphrase: &str) -> Result<String, Error> {
+        self.call_command("setup_device", (&self.hw_client, label, passphrase))
+    }
+
+    fn restore_device(&self, label: &str, word_count: u8) -> Result<String, Error> {
+        self.call_command("restore_device", (&self.hw_client, label, word_count))
+    }
+
+    fn backup_device(&self, label: &str, backup_passphrase: &str) -> Result<String, Error> {
+        self.call_command("backup_device", (&self.hw_client, label, backup_passphrase))
+    }
+
+    fn wipe_device(&self) -> Result<String, Error> {
+        self.call_command("wipe_device", (&self.hw_client,))
+    }
+
+    fn prompt_pin(&self) -> Result<String, Error> {
+        self.call_command("prompt_pin", (&self.hw_client,))
+    }
+
+    fn send_pin(&self, pin: &str) -> Result<String, Error> {
+        self.call_command("send_pin", (&self.hw_client, pin))
+    }
+
+    fn get_version() -> Option<String> {
+        Python::with_gil(|py| {
+            Some(
+                PyModule::import_bound(py, "hwilib")
+                    .ok()?
+                    .getattr("__version__")
+                    .ok()?
+                    .to_string(),
+            )
+        })
+    }
+
+    fn install_hwilib(version: Option<&str>) -> Result<(), Error> {
+        install_hwilib_with_pip(version)
+    }
+}
@@ -0,0 +1,598 @@
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use bitcoin::bip32::DerivationPath;
+use bitcoin::hex::DisplayHex;
+use bitcoin::{Network, Psbt};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::error::{Error, ErrorCode, HWIErrorDetails};
+use crate::types::{
+    HWIAddressType, HWIChain, HWIDevice, HWIDeviceType, HWIImplementation, HWIPassphrase,
+    HWIWalletPolicy, KeypoolRequest, LogLevel,
+};
+
+/// File the calls made through [`RecordingHWI`] are appended to.
+static RECORDING: Mutex<Option<PathBuf>> = Mutex::new(None);
+/// Last error writing to the recording, see [`RecordingHWI::take_recording_error`].
+static RECORDING_ERROR: Mutex<Option<Error>> = Mutex::new(None);
+/// Calls [`ReplayHWI`] still has to serve, the next one last.
+static REPLAY: Mutex<Vec<HWIRecordedCall>> = Mutex::new(Vec::new());
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// A call to an [`HWIImplementation`], as recorded by [`RecordingHWI`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HWIRecordedCall {
+    /// Name of the HWI command, e.g. `getxpub`.
+    pub command: String,
+    /// Arguments of the command, by name.
+    pub args: Value,
+    #[serde(flatten)]
+    pub outcome: HWICallOutcome,
+}
+
+/// What a recorded call returned.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HWICallOutcome {
+    /// The JSON document returned by the implementation.
+    Output(Value),
+    /// The call failed.
+    Error { message: String, code: Option<i8> },
+}
+
+impl HWICallOutcome {
+    /// Rebuilds the error of a failed call, as the live implementations report it.
+    fn into_result(self) -> Result<Value, Error> {
+        match self {
+            HWICallOutcome::Output(value) => Ok(value),
+            HWICallOutcome::Error { message, code } => Err(Error::from_details(HWIErrorDetails {
+                message,
+                code: code.and_then(|c| ErrorCode::try_from(c).ok()),
+                traceback: None,
+            })),
+        }
+    }
+
+    fn from_result<R>(result: &Result<R, Error>, to_value: impl FnOnce(&R) -> Value) -> Self {
+        match result {
+            Ok(output) => HWICallOutcome::Output(to_value(output)),
+            Err(Error::Hwi(message, code)) => HWICallOutcome::Error {
+                message: message.clone(),
+                code: code.map(|c| c.as_i8()),
+            },
+            Err(Error::ActionCanceled(details))
+            | Err(Error::DeviceBusy(details))
+            | Err(Error::DeviceConnection(details))
+            | Err(Error::DeviceNotReady(details))
+            | Err(Error::DeviceNotInitialized(details))
+            | Err(Error::UnsupportedAction(details))
+            | Err(Error::HwiException(details)) => HWICallOutcome::Error {
+                message: details.message.clone(),
+                code: details.code.map(|c| c.as_i8()),
+            },
+            Err(e) => HWICallOutcome::Error {
+                message: e.to_string(),
+                code: e.code().map(|c| c.as_i8()),
+            },
+        }
+    }
+}
+
+/// Parses the output of an implementation, keeping it as a string if it isn't valid JSON.
+fn output_value(output: &str) -> Value {
+    serde_json::from_str(output).unwrap_or_else(|_| Value::String(output.to_string()))
+}
+
+fn output_string(value: Value) -> String {
+    match value {
+        Value::String(s) => s,
+        value => value.to_string(),
+    }
+}
+
+fn device_args(device: &HWIDevice) -> Value {
+    json!({
+        "type": device.device_type.to_string(),
+        "model": device.model,
+        "path": device.path,
+        "fingerprint": device.fingerprint.to_string(),
+    })
+}
+
+fn policy_args(policy: Option<&HWIWalletPolicy>) -> Value {
+    policy.map_or(Value::Null, |policy| {
+        json!({
+            "name": policy.name,
+            "descriptor": policy.descriptor,
+            "hmac": policy.hmac.to_lower_hex_string(),
+        })
+    })
+}
+
+/// Describes `passphrase` without revealing it, so that it doesn't end up in recordings.
+fn passphrase_args(passphrase: &HWIPassphrase) -> Value {
+    match passphrase {
+        HWIPassphrase::Empty => json!("empty"),
+        HWIPassphrase::Host(_) => json!("host"),
+        HWIPassphrase::OnDevice => json!("on_device"),
+    }
+}
+
+/// Describes `secret` without revealing it, like [`passphrase_args`].
+fn secret_args(secret: Option<&str>) -> Value {
+    match secret {
+        None => Value::Null,
+        Some("") => json!("empty"),
+        Some(_) => json!("redacted"),
+    }
+}
+
+fn find_device_args(
+    password: Option<&str>,
+    device_type: Option<&HWIDeviceType>,
+    fingerprint: Option<&str>,
+    expert: bool,
+    chain: Network,
+) -> Value {
+    json!({
+        "password": secret_args(password),
+        "device_type": device_type.map(|t| t.to_string()),
+        "fingerprint": fingerprint,
+        "expert": expert,
+        "chain": chain.to_string(),
+    })
+}
+
+fn label_args(label: &str, backup_passphrase: &str) -> Value {
+    json!({ "label": label, "backup_passphrase": secret_args(Some(backup_passphrase)) })
+}
+
+fn keypool_args(request: &KeypoolRequest) -> Value {
+    json!({
+        "keypool": request.keypool,
+        "internal": request.internal,
+        "addr_type": request.addr_type.to_string(),
+        "addr_all": request.addr_all,
+        "account": request.account,
+        "path": request.path.as_ref().map(|p| format!("m/{}", p)),
+        "start": request.start,
+        "end": request.end,
+    })
+}
+
+/// Appends `call` to the recording at `path`.
+fn append_call(path: &Path, call: &HWIRecordedCall) -> Result<(), Error> {
+    let mut file = OpenOptions::new().append(true).open(path)?;
+    writeln!(file, "{}", serde_json::to_string(call)?)?;
+    Ok(())
+}
+
+/// HWI implementation recording every call made through the implementation `T`.
+///
+/// Calls are appended, one JSON object per line, to the file set with
+/// [`start_recording`](RecordingHWI::start_recording), and can be served back by [`ReplayHWI`].
+/// While no recording is in progress, calls are just forwarded to `T`.
+///
+/// Passwords, PINs and passphrases are never written to the recording, only whether they were
+/// given, so a replayed call can't tell them apart.
+#[derive(Debug)]
+pub struct RecordingHWI<T: HWIImplementation> {
+    inner: T,
+}
+
+impl<T: HWIImplementation> RecordingHWI<T> {
+    /// Starts recording to the file at `path`, replacing its content.
+    pub fn start_recording<P: AsRef<Path>>(path: P) -> Result<(), Error> {
+        File::create(path.as_ref())?;
+        *lock(&RECORDING) = Some(path.as_ref().to_path_buf());
+        *lock(&RECORDING_ERROR) = None;
+        Ok(())
+    }
+
+    /// Stops recording.
+    pub fn stop_recording() {
+        *lock(&RECORDING) = None;
+    }
+
+    /// Returns the last error writing a call to the recording, if any, and clears it.
+    ///
+    /// Calls return the result of the wrapped implementation even when it couldn't be recorded,
+    /// e.g. so that a transaction the user signed on the device isn't lost, the recording is then
+    /// missing calls.
+    pub fn take_recording_error() -> Option<Error> {
+        lock(&RECORDING_ERROR).take()
+    }
+
+    /// Returns the wrapped implementation.
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    fn record<R>(
+        command: &str,
+        args: Value,
+        result: Result<R, Error>,
+        to_value: impl FnOnce(&R) -> Value,
+    ) -> Result<R, Error> {
+        if let Some(path) = lock(&RECORDING).as_ref() {
+            let call = HWIRecordedCall {
+                command: command.to_string(),
+                args,
+                outcome: HWICallOutcome::from_result(&result, to_value),
+            };
+            if let Err(e) = append_call(path, &call) {
+                *lock(&RECORDING_ERROR) = Some(e);
+            }
+        }
+        result
+    }
+
+    fn record_output(
+        command: &str,
+        args: Value,
+        result: Result<String, Error>,
+    ) -> Result<String, Error> {
+        Self::record(command, args, result, |output| output_value(output))
+    }
+}
+
+impl<T: HWIImplementation> HWIImplementation for RecordingHWI<T> {
+    fn enumerate(password: Option<&str>) -> Result<String, Error> {
+        let args = json!({ "password": secret_args(password) });
+        Self::record_output("enumerate", args, T::enumerate(password))
+    }
+
+    fn get_client(
+        device: &HWIDevice,
+        expert: bool,
+        chain: HWIChain,
+        passphrase: &HWIPassphrase,
+    ) -> Result<Self, Error> {
+        let args = json!({
+            "device": device_args(device),
+            "expert": expert,
+            "chain": chain.network().to_string(),
+            "passphrase": passphrase_args(passphrase),
+        });
+        let result =
+            T::get_client(device, expert, chain, passphrase).map(|inner| RecordingHWI { inner });
+        Self::record("get_client", args, result, |_| Value::Null)
+    }
+
+    fn find_device(
+        password: Option<&str>,
+        device_type: Option<HWIDeviceType>,
+        fingerprint: Option<&str>,
+        expert: bool,
+        chain: Network,
+    ) -> Result<Self, Error> {
+        let args = find_device_args(password, device_type.as_ref(), fingerprint, expert, chain);
+        let result = T::find_device(password, device_type, fingerprint, expert, chain)
+            .map(|inner| RecordingHWI { inner });
+        Self::record("find_device", args, result, |_| Value::Null)
+    }
+
+    fn chain(&self) -> HWIChain {
+        self.inner.chain()
+    }
+
+    fn get_master_xpub(&self, addrtype: HWIAddressType, account: u32) -> Result<String, Error> {
+        let args = json!({ "addr_type": addrtype.to_string(), "account": account });
+        let result = self.inner.get_master_xpub(addrtype, account);
+        Self::record_output("getmasterxpub", args, result)
+    }
+
+    fn sign_tx(&self, psbt: &Psbt, policy: Option<&HWIWalletPolicy>) -> Result<String, Error> {
+        let args = json!({ "psbt": psbt.to_string(), "policy": policy_args(policy) });
+        Self::record_output("signtx", args, self.inner.sign_tx(psbt, policy))
+    }
+
+    fn get_xpub(&self, path: &DerivationPath, expert: bool) -> Result<String, Error> {
+        let args = json!({ "path": format!("m/{}", path), "expert": expert });
+        Self::record_output("getxpub", args, self.inner.get_xpub(path, expert))
+    }
+
+    fn sign_message(&self, message: &str, path: &DerivationPath) -> Result<String, Error> {
+        let args = json!({ "message": message, "path": format!("m/{}", path) });
+        let result = self.inner.sign_message(message, path);
+        Self::record_output("signmessage", args, result)
+    }
+
+    fn get_keypool(&self, request: &KeypoolRequest) -> Result<String, Error> {
+        let args = keypool_args(request);
+        let result = self.inner.get_keypool(request);
+        Self::record_output("getkeypool", args, result)
+    }
+
+    fn get_descriptors(&self, account: Option<u32>) -> Result<String, Error> {
+        let args = json!({ "account": account });
+        let result = self.inner.get_descriptors(account);
+        Self::record_output("getdescriptors", args, result)
+    }
+
+    fn display_address_with_desc(
+        &self,
+        descriptor: &str,
+        policy: Option<&HWIWalletPolicy>,
+    ) -> Result<String, Error> {
+        let args = json!({ "desc": descriptor, "policy": policy_args(policy) });
+        let result = self.inner.display_address_with_desc(descriptor, policy);
+        Self::record_output("displayaddress", args, result)
+    }
+
+    fn register_policy(&self, name: &str, descriptor: &str) -> Result<String, Error> {
+        let args = json!({ "name": name, "desc": descriptor });
+        let result = self.inner.register_policy(name, descriptor);
+        Self::record_output("register", args, result)
+    }
+
+    fn display_address_with_path(
+        &self,
+        path: &DerivationPath,
+        address_type: HWIAddressType,
+    ) -> Result<String, Error> {
+        let args = json!({ "path": format!("m/{}", path), "addr_type": address_type.to_string() });
+        let result = self.inner.display_address_with_path(path, address_type);
+        Self::record_output("displayaddress", args, result)
+    }
+
+    fn install_udev_rules(source: &str, location: &str) -> Result<String, Error> {
+        let args = json!({ "source": source, "location": location });
+        let result = T::install_udev_rules(source, location);
+        Self::record_output("installudevrules", args, result)
+    }
+
+    fn set_log_level(level: LogLevel) -> Result<(), Error> {
+        T::set_log_level(level)
+    }
+
+    fn toggle_passphrase(&self) -> Result<String, Error> {
+        let result = self.inner.toggle_passphrase();
+        Self::record_output("togglepassphrase", json!({}), result)
+    }
+
+    fn setup_device(&self, label: &str, passphrase: &str) -> Result<String, Error> {
+        let args = label_args(label, passphrase);
+        let result = self.inner.setup_device(label, passphrase);
+        Self::record_output("setup", args, result)
+    }
+
+    fn restore_device(&self, label: &str, word_count: u8) -> Result<String, Error> {
+        let args = json!({ "label": label, "word_count": word_count });
+        let result = self.inner.restore_device(label, word_count);
+        Self::record_output("restore", args, result)
+    }
+
+    fn backup_device(&self, label: &str, backup_passphrase: &str) -> Result<String, Error> {
+        let args = label_args(label, backup_passphrase);
+        let result = self.inner.backup_device(label, backup_passphrase);
+        Self::record_output("backup", args, result)
+    }
+
+    fn wipe_device(&self) -> Result<String, Error> {
+        Self::record_output("wipe", json!({}), self.inner.wipe_device())
+    }
+
+    fn prompt_pin(&self) -> Result<String, Error> {
+        Self::record_output("promptpin", json!({}), self.inner.prompt_pin())
+    }
+
+    fn send_pin(&self, pin: &str) -> Result<String, Error> {
+        let args = json!({ "pin": secret_args(Some(pin)) });
+        Self::record_output("sendpin", args, self.inner.send_pin(pin))
+    }
+
+    fn get_version() -> Option<String> {
+        T::get_version()
+    }
+
+    fn install_hwilib(version: Option<&str>) -> Result<(), Error> {
+        T::install_hwilib(version)
+    }
+}
+
+/// HWI implementation serving back the calls recorded by [`RecordingHWI`], without any device.
+///
+/// Calls must be made in the same order and with the same arguments as when they were recorded,
+/// otherwise they fail, pointing out the difference.
+#[derive(Debug)]
+pub struct ReplayHWI {
+    chain: HWIChain,
+}
+
+impl ReplayHWI {
+    /// Loads the calls recorded in the file at `path`, replacing the ones still to be served.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<(), Error> {
+        let file = File::open(path)?;
+        let mut calls = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                calls.push(serde_json::from_str(&line)?);
+            }
+        }
+        calls.reverse();
+        *lock(&REPLAY) = calls;
+        Ok(())
+    }
+
+    /// Returns the number of calls still to be served.
+    pub fn remaining() -> usize {
+        lock(&REPLAY).len()
+    }
+
+    fn replay(command: &str, args: Value) -> Result<Value, Error> {
+        let call = lock(&REPLAY).pop().ok_or_else(|| {
+            Error::Hwi(
+                format!("replay: unexpected call to `{}` with {}", command, args),
+                None,
+            )
+        })?;
+        if call.command != command || call.args != args {
+            return Err(Error::Hwi(
+                format!(
+                    "replay: expected `{}` with {}, got `{}` with {}",
+                    call.command, call.args, command, args
+                ),
+                None,
+            ));
+        }
+        call.outcome.into_result()
+    }
+
+    fn replay_output(command: &str, args: Value) -> Result<String, Error> {
+        Self::replay(command, args).map(output_string)
+    }
+}
+
+impl HWIImplementation for ReplayHWI {
+    fn enumerate(password: Option<&str>) -> Result<String, Error> {
+        Self::replay_output("enumerate", json!({ "password": secret_args(password) }))
+    }
+
+    fn get_client(
+        device: &HWIDevice,
+        expert: bool,
+        chain: HWIChain,
+        passphrase: &HWIPassphrase,
+    ) -> Result<Self, Error> {
+        let args = json!({
+            "device": device_args(device),
+            "expert": expert,
+            "chain": chain.network().to_string(),
+            "passphrase": passphrase_args(passphrase),
+        });
+        Self::replay("get_client", args)?;
+        Ok(ReplayHWI { chain })
+    }
+
+    fn find_device(
+        password: Option<&str>,
+        device_type: Option<HWIDeviceType>,
+        fingerprint: Option<&str>,
+        expert: bool,
+        chain: Network,
+    ) -> Result<Self, Error> {
+        let args = find_device_args(password, device_type.as_ref(), fingerprint, expert, chain);
+        Self::replay("find_device", args)?;
+        Ok(ReplayHWI {
+            chain: chain.into(),
+        })
+    }
+
+    fn chain(&self) -> HWIChain {
+        self.chain.clone()
+    }
+
+    fn get_master_xpub(&self, addrtype: HWIAddressType, account: u32) -> Result<String, Error> {
+        let args = json!({ "addr_type": addrtype.to_string(), "account": account });
+        Self::replay_output("getmasterxpub", args)
+    }
+
+    fn sign_tx(&self, psbt: &Psbt, policy: Option<&HWIWalletPolicy>) -> Result<String, Error> {
+        let args = json!({ "psbt": psbt.to_string(), "policy": policy_args(policy) });
+        Self::replay_output("signtx", args)
+    }
+
+    fn get_xpub(&self, path: &DerivationPath, expert: bool) -> Result<String, Error> {
+        let args = json!({ "path": format!("m/{}", path), "expert": expert });
+        Self::replay_output("getxpub", args)
+    }
+
+    fn sign_message(&self, message: &str, path: &DerivationPath) -> Result<String, Error> {
+        let args = json!({ "message": message, "path": format!("m/{}", path) });
+        Self::replay_output("signmessage", args)
+    }
+
+    fn get_keypool(&self, request: &KeypoolRequest) -> Result<String, Error> {
+        let args = keypool_args(request);
+        Self::replay_output("getkeypool", args)
+    }
+
+    fn get_descriptors(&self, account: Option<u32>) -> Result<String, Error> {
+        Self::replay_output("getdescriptors", json!({ "account": account }))
+    }
+
+    fn display_address_with_desc(
+        &self,
+        descriptor: &str,
+        policy: Option<&HWIWalletPolicy>,
+    ) -> Result<String, Error> {
+        let args = json!({ "desc": descriptor, "policy": policy_args(policy) });
+        Self::replay_output("displayaddress", args)
+    }
+
+    fn register_policy(&self, name: &str, descriptor: &str) -> Result<String, Error> {
+        Self::replay_output("register", json!({ "name": name, "desc": descriptor }))
+    }
+
+    fn display_address_with_path(
+        &self,
+        path: &DerivationPath,
+        address_type: HWIAddressType,
+    ) -> Result<String, Error> {
+        let args = json!({ "path": format!("m/{}", path), "addr_type": address_type.to_string() });
+        Self::replay_output("displayaddress", args)
+    }
+
+    fn install_udev_rules(source: &str, location: &str) -> Result<String, Error> {
+        let args = json!({ "source": source, "location": location });
+        Self::replay_output("installudevrules", args)
+    }
+
+    fn set_log_level(_level: LogLevel) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn toggle_passphrase(&self) -> Result<String, Error> {
+        Self::replay_output("togglepassphrase", json!({}))
+    }
+
+    fn setup_device(&self, label: &str, passphrase: &str) -> Result<String, Error> {
+        let args = label_args(label, passphrase);
+        Self::replay_output("setup", args)
+    }
+
+    fn restore_device(&self, label: &str, word_count: u8) -> Result<String, Error> {
+        let args = json!({ "label": label, "word_count": word_count });
+        Self::replay_output("restore", args)
+    }
+
+    fn backup_device(&self, label: &str, backup_passphrase: &str) -> Result<String, Error> {
+        let args = label_args(label, backup_passphrase);
+        Self::replay_output("backup", args)
+    }
+
+    fn wipe_device(&self) -> Result<String, Error> {
+        Self::replay_output("wipe", json!({}))
+    }
+
+    fn prompt_pin(&self) -> Result<String, Error> {
+        Self::replay_output("promptpin", json!({}))
+    }
+
+    fn send_pin(&self, pin: &str) -> Result<String, Error> {
+        Self::replay_output("sendpin", json!({ "pin": secret_args(Some(pin)) }))
+    }
+
+    fn get_version() -> Option<String> {
+        None
+    }
+
+    fn install_hwilib(_version: Option<&str>) -> Result<(), Error> {
+        Err(Error::from_details(HWIErrorDetails {
+            message: "the replay implementation doesn't use hwilib".to_string(),
+            code: Some(ErrorCode::NotImplemented),
+            traceback: None,
+        }))
+    }
+}
@@ -1,73 +1,242 @@
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
+use std::marker::PhantomData;
 use std::ops::Deref;
-use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use bitcoin::bip32::DerivationPath;
-use bitcoin::Psbt;
+use bitcoin::bip32::{DerivationPath, Fingerprint};
+use bitcoin::key::{CompressedPublicKey, Secp256k1, XOnlyPublicKey};
+use bitcoin::{Psbt, ScriptBuf};
 
 use serde::de::DeserializeOwned;
 use serde_json::value::Value;
 
-use crate::error::Error;
+use crate::bip322::{self, Bip322Format};
+use crate::error::{Error, ErrorCode, HWIErrorDetails};
+#[cfg(feature = "python")]
+use crate::implementations::python_implementation::PythonHWI;
 use crate::types::{
     HWIAddress, HWIAddressType, HWIChain, HWIDescriptor, HWIDevice, HWIDeviceInternal,
-    HWIDeviceType, HWIExtendedPubKey, HWIKeyPoolElement, HWIPartiallySignedTransaction,
-    HWISignature, HWIStatus, HWIWordCount, LogLevel, ToDescriptor,
+    HWIDeviceType, HWIErrorResponse, HWIExtendedPubKey, HWIImplementation, HWIKeyPoolElement,
+    HWIPartiallySignedTransaction, HWIPassphrase, HWIPolicyRegistration, HWISignature, HWIStatus,
+    HWITimeouts, HWIWalletPolicy, HWIWordCount, KeypoolRequest, LogLevel, ToDescriptor,
 };
 
-use pyo3::{prelude::*, py_run};
-
 macro_rules! deserialize_obj {
     ( $e: expr ) => {{
         let value: Value = serde_json::from_str($e)?;
+        HWIErrorResponse::check(&value)?;
         let obj = value.clone();
         serde_json::from_value(value)
             .map_err(|e| Error::Hwi(format!("error {} while deserializing {}", e, obj), None))
     }};
 }
 
-/// Convenience class containing required Python objects
-#[derive(Debug)]
-struct HWILib {
-    commands: Py<PyModule>,
-    json_dumps: Py<PyAny>,
+/// How often a call waiting for the device checks whether it was cancelled.
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
 }
 
-impl HWILib {
-    pub fn initialize() -> Result<Self, Error> {
-        Python::with_gil(|py| {
-            let commands: Py<PyModule> = PyModule::import_bound(py, "hwilib.commands")?.into();
-            let json_dumps: Py<PyAny> =
-                PyModule::import_bound(py, "json")?.getattr("dumps")?.into();
-            Ok(HWILib {
-                commands,
-                json_dumps,
-            })
-        })
+/// Kind of call, deciding which of the [`HWITimeouts`] applies.
+#[derive(Clone, Copy, Debug)]
+enum Operation {
+    Query,
+    Confirmation,
+}
+
+#[derive(Debug, Default)]
+struct CancelState {
+    /// Incremented by every cancellation, calls started before it are cancelled.
+    generation: AtomicUsize,
+    /// Whether a handle was handed out, otherwise calls can't be cancelled.
+    enabled: AtomicBool,
+}
+
+/// Cancels the calls in progress on a [`HWIClient`], see [`HWIClient::cancel_handle`].
+#[derive(Clone, Debug, Default)]
+pub struct HWICancelHandle {
+    state: Arc<CancelState>,
+}
+
+impl HWICancelHandle {
+    /// Makes the calls in progress return [`Error::Cancelled`]. Calls started afterwards aren't
+    /// affected.
+    pub fn cancel(&self) {
+        self.state.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn generation(&self) -> usize {
+        self.state.generation.load(Ordering::SeqCst)
     }
 }
 
+/// Client for a single hardware wallet, reaching HWI through the implementation `T`.
+///
+/// The client is `Send` and `Sync`, but devices can't handle concurrent requests: use
+/// [`SharedHWIClient`](crate::SharedHWIClient) to share it between threads.
+///
+/// Calls wait for the device without limit, unless [timeouts](Self::with_timeouts) are set or a
+/// [cancel handle](Self::cancel_handle) is used. A call that timed out or was cancelled can't be
+/// aborted: it keeps running in the background until HWI returns, e.g. once the user rejects it
+/// on the device or unplugs it, and the next calls fail with [`Error::DeviceBusy`] until then.
+/// In particular, a confirmation given up on can still be approved on the device, e.g. a
+/// transaction can still be signed, although its result is lost.
 #[derive(Debug)]
-pub struct HWIClient {
-    hwilib: HWILib,
-    hw_client: PyObject,
+pub struct HWIClient<T: HWIImplementation> {
+    implementation: Arc<T>,
+    timeouts: HWITimeouts,
+    cancel: HWICancelHandle,
+    /// Held while the implementation runs a call, including calls given up on.
+    device: Arc<Mutex<()>>,
+    /// Set once the last call given up on returns.
+    abandoned: Mutex<Option<Arc<AtomicBool>>>,
 }
 
-impl Deref for HWIClient {
-    type Target = PyObject;
+/// [`HWIClient`] embedding Python through PyO3, the default before the implementation could be
+/// chosen.
+#[cfg(feature = "python")]
+pub type PythonHWIClient = HWIClient<PythonHWI>;
+
+impl<T: HWIImplementation> Deref for HWIClient<T> {
+    type Target = T;
 
     fn deref(&self) -> &Self::Target {
-        &self.hw_client
+        &self.implementation
+    }
+}
+
+impl<T: HWIImplementation> From<T> for HWIClient<T> {
+    fn from(implementation: T) -> Self {
+        HWIClient {
+            implementation: Arc::new(implementation),
+            timeouts: HWITimeouts::default(),
+            cancel: HWICancelHandle::default(),
+            device: Arc::new(Mutex::new(())),
+            abandoned: Mutex::new(None),
+        }
     }
 }
 
-impl HWIClient {
+impl<T: HWIImplementation> HWIClient<T> {
+    /// Sets the timeouts of the calls made through the client.
+    pub fn with_timeouts(mut self, timeouts: HWITimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Returns the timeouts of the calls made through the client.
+    pub fn timeouts(&self) -> HWITimeouts {
+        self.timeouts
+    }
+
+    /// Returns the chain the client was opened for.
+    pub fn chain(&self) -> HWIChain {
+        self.implementation.chain()
+    }
+
+    /// Returns a handle cancelling the calls in progress on this client from another thread.
+    pub fn cancel_handle(&self) -> HWICancelHandle {
+        self.cancel.state.enabled.store(true, Ordering::SeqCst);
+        self.cancel.clone()
+    }
+
+    /// Runs `f` on the implementation, giving up once the timeout of `operation` expires or the
+    /// call is cancelled.
+    ///
+    /// Fails right away with [`Error::DeviceBusy`] while a call given up on still runs.
+    fn call<R: Send + 'static>(
+        &self,
+        operation: Operation,
+        f: impl FnOnce(&T) -> Result<R, Error> + Send + 'static,
+    ) -> Result<R, Error> {
+        let mut abandoned = lock(&self.abandoned);
+        match abandoned.as_ref() {
+            Some(finished) if !finished.load(Ordering::SeqCst) => {
+                return Err(Error::from_details(HWIErrorDetails {
+                    message: "the device is still busy with a call that was given up on"
+                        .to_string(),
+                    code: Some(ErrorCode::DeviceBusy),
+                    traceback: None,
+                }))
+            }
+            _ => *abandoned = None,
+        }
+        drop(abandoned);
+
+        let timeout = match operation {
+            Operation::Query => self.timeouts.query,
+            Operation::Confirmation => self.timeouts.confirmation,
+        };
+        if timeout.is_none() && !self.cancel.state.enabled.load(Ordering::SeqCst) {
+            let _device = lock(&self.device);
+            return f(&self.implementation);
+        }
+
+        // Run the call on its own thread, so that it can be left behind
+        let generation = self.cancel.generation();
+        let implementation = self.implementation.clone();
+        let device = self.device.clone();
+        let finished = Arc::new(AtomicBool::new(false));
+        let (sender, receiver) = mpsc::channel();
+        {
+            let finished = finished.clone();
+            thread::spawn(move || {
+                let _device = lock(&device);
+                let result = f(&implementation);
+                finished.store(true, Ordering::SeqCst);
+                // The caller is gone if it gave up
+                let _ = sender.send(result);
+            });
+        }
+
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let wait = deadline.map_or(CANCEL_POLL_INTERVAL, |deadline| {
+                deadline
+                    .saturating_duration_since(Instant::now())
+                    .min(CANCEL_POLL_INTERVAL)
+            });
+            match receiver.recv_timeout(wait) {
+                Ok(result) => return result,
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(Error::Hwi(
+                        "the HWI call panicked".to_string(),
+                        Some(ErrorCode::UnknownError),
+                    ))
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+            }
+            let error = if self.cancel.generation() != generation {
+                Error::Cancelled
+            } else {
+                match (deadline, timeout) {
+                    (Some(deadline), Some(timeout)) if Instant::now() >= deadline => {
+                        Error::Timeout(timeout)
+                    }
+                    _ => continue,
+                }
+            };
+            *lock(&self.abandoned) = Some(finished);
+            return Err(error);
+        }
+    }
+
+    /// Returns a [`HWIClientBuilder`] to configure and open a client.
+    pub fn builder() -> HWIClientBuilder<T> {
+        HWIClientBuilder::new()
+    }
+
     /// Lists all HW devices currently connected.
     /// ```no_run
-    /// # use hwi::HWIClient;
+    /// # use hwi::PythonHWIClient;
     /// # use hwi::error::Error;
     /// # fn main() -> Result<(), Error> {
-    /// let devices = HWIClient::enumerate()?;
+    /// let devices = PythonHWIClient::enumerate()?;
     /// for device in devices {
     ///     match device {
     ///         Ok(d) => println!("I can see a {} here 😄", d.model),
@@ -78,13 +247,27 @@ impl HWIClient {
     /// # }
     /// ```
     pub fn enumerate() -> Result<Vec<Result<HWIDevice, Error>>, Error> {
-        let libs = HWILib::initialize()?;
-        Python::with_gil(|py| {
-            let output = libs.commands.getattr(py, "enumerate")?.call0(py)?;
-            let output = libs.json_dumps.call1(py, (output,))?;
-            let devices_internal: Vec<HWIDeviceInternal> = deserialize_obj!(&output.to_string())?;
-            Ok(devices_internal.into_iter().map(|d| d.try_into()).collect())
-        })
+        let devices_internal = Self::enumerate_internal(None)?;
+        Ok(devices_internal.into_iter().map(|d| d.try_into()).collect())
+    }
+
+    /// Lists all HW devices currently connected, like [`enumerate`](Self::enumerate), sending
+    /// `password` to the devices needing a passphrase.
+    ///
+    /// Without it HWI can't get the fingerprint of a device reporting `needs_passphrase_sent`,
+    /// which is listed as an error.
+    pub fn enumerate_with_password(password: &str) -> Result<Vec<Result<HWIDevice, Error>>, Error> {
+        let devices_internal = Self::enumerate_internal(Some(password))?;
+        Ok(devices_internal.into_iter().map(|d| d.try_into()).collect())
+    }
+
+    /// Like [`enumerate`](Self::enumerate), keeping what HWI knows about the devices it reports an
+    /// error for.
+    pub(crate) fn enumerate_internal(
+        password: Option<&str>,
+    ) -> Result<Vec<HWIDeviceInternal>, Error> {
+        let output = T::enumerate(password)?;
+        deserialize_obj!(&output)
     }
 
     /// Returns the HWIClient for a certain device. You can list all the available devices using
@@ -92,14 +275,15 @@ impl HWIClient {
     ///
     /// Setting `expert` to `true` will enable additional output for some commands.
     /// ```
-    /// # use hwi::HWIClient;
+    /// # use hwi::PythonHWIClient;
     /// # use hwi::types::*;
     /// # use hwi::error::Error;
     /// # fn main() -> Result<(), Error> {
-    /// let devices = HWIClient::enumerate()?;
+    /// let devices = PythonHWIClient::enumerate()?;
     /// for device in devices {
     ///     let device = device?;
-    ///     let client = HWIClient::get_client(&device, false, bitcoin::Network::Testnet.into())?;
+    ///     let client =
+    ///         PythonHWIClient::get_client(&device, false, bitcoin::Network::Testnet.into())?;
     ///     let xpub = client.get_master_xpub(HWIAddressType::Tap, 0)?;
     ///     println!(
     ///         "I can see a {} here, and its xpub is {}",
@@ -114,25 +298,24 @@ impl HWIClient {
         device: &HWIDevice,
         expert: bool,
         chain: HWIChain,
-    ) -> Result<HWIClient, Error> {
-        let libs = HWILib::initialize()?;
-        Python::with_gil(|py| {
-            let client_args = (
-                device.device_type.to_string(),
-                &device.path,
-                "",
-                expert,
-                chain,
-            );
-            let client = libs
-                .commands
-                .getattr(py, "get_client")?
-                .call1(py, client_args)?;
-            Ok(HWIClient {
-                hwilib: libs,
-                hw_client: client,
-            })
-        })
+    ) -> Result<HWIClient<T>, Error> {
+        Self::get_client_with_passphrase(device, expert, chain, &HWIPassphrase::Empty)
+    }
+
+    /// Returns the HWIClient for a certain device, like [`get_client`](HWIClient::get_client),
+    /// opening it with a BIP 39 `passphrase`.
+    ///
+    /// This allows opening devices reporting `needs_passphrase_sent` straight from the result of
+    /// [`enumerate`](HWIClient::enumerate). With [`HWIPassphrase::OnDevice`] the user types the
+    /// passphrase on the device instead, if the device supports it.
+    pub fn get_client_with_passphrase(
+        device: &HWIDevice,
+        expert: bool,
+        chain: HWIChain,
+        passphrase: &HWIPassphrase,
+    ) -> Result<HWIClient<T>, Error> {
+        let implementation = T::get_client(device, expert, chain, passphrase)?;
+        Ok(HWIClient::from(implementation))
     }
 
     /// Returns the HWIClient for a certain `device_type` or `fingerprint`. You can list all the available devices using
@@ -140,11 +323,11 @@ impl HWIClient {
     ///
     /// Setting `expert` to `true` will enable additional output for some commands.
     /// ```no_run
-    /// # use hwi::HWIClient;
+    /// # use hwi::PythonHWIClient;
     /// # use hwi::types::*;
     /// # use hwi::error::Error;
     /// # fn main() -> Result<(), Error> {
-    /// let client = HWIClient::find_device(
+    /// let client = PythonHWIClient::find_device(
     ///     None,
     ///     Some(HWIDeviceType::Trezor),
     ///     None,
@@ -162,30 +345,9 @@ impl HWIClient {
         fingerprint: Option<&str>,
         expert: bool,
         chain: bitcoin::Network,
-    ) -> Result<HWIClient, Error> {
-        let libs = HWILib::initialize()?;
-        Python::with_gil(|py| {
-            let client_args = (
-                password.unwrap_or(""),
-                device_type.map_or_else(String::new, |d| d.to_string()),
-                fingerprint.unwrap_or(""),
-                expert,
-                HWIChain::from(chain),
-            );
-            let client = libs
-                .commands
-                .getattr(py, "find_device")?
-                .call1(py, client_args)?;
-
-            if client.is_none(py) {
-                return Err(Error::Hwi("device not found".to_string(), None));
-            }
-
-            Ok(HWIClient {
-                hwilib: libs,
-                hw_client: client,
-            })
-        })
+    ) -> Result<HWIClient<T>, Error> {
+        let implementation = T::find_device(password, device_type, fingerprint, expert, chain)?;
+        Ok(HWIClient::from(implementation))
     }
 
     /// Returns the master xpub of a device, given the address type and the account number.
@@ -194,28 +356,31 @@ impl HWIClient {
         addrtype: HWIAddressType,
         account: u32,
     ) -> Result<HWIExtendedPubKey, Error> {
-        Python::with_gil(|py| {
-            let output = self
-                .hwilib
-                .commands
-                .getattr(py, "getmasterxpub")?
-                .call1(py, (&self.hw_client, addrtype, account))?;
-            let output = self.hwilib.json_dumps.call1(py, (output,))?;
-            deserialize_obj!(&output.to_string())
-        })
+        let output = self.call(Operation::Query, move |hwi| {
+            hwi.get_master_xpub(addrtype, account)
+        })?;
+        deserialize_obj!(&output)
     }
 
     /// Signs a PSBT.
     pub fn sign_tx(&self, psbt: &Psbt) -> Result<HWIPartiallySignedTransaction, Error> {
-        Python::with_gil(|py| {
-            let output = self
-                .hwilib
-                .commands
-                .getattr(py, "signtx")?
-                .call1(py, (&self.hw_client, psbt.to_string()))?;
-            let output = self.hwilib.json_dumps.call1(py, (output,))?;
-            deserialize_obj!(&output.to_string())
-        })
+        let psbt = psbt.clone();
+        let output = self.call(Operation::Confirmation, move |hwi| hwi.sign_tx(&psbt, None))?;
+        deserialize_obj!(&output)
+    }
+
+    /// Signs a PSBT spending from a wallet policy registered with
+    /// [`register_policy`](Self::register_policy).
+    pub fn sign_tx_with_policy(
+        &self,
+        psbt: &Psbt,
+        policy: &HWIWalletPolicy,
+    ) -> Result<HWIPartiallySignedTransaction, Error> {
+        let (psbt, policy) = (psbt.clone(), policy.clone());
+        let output = self.call(Operation::Confirmation, move |hwi| {
+            hwi.sign_tx(&psbt, Some(&policy))
+        })?;
+        deserialize_obj!(&output)
     }
 
     /// Returns the xpub of a device. If `expert` is set, additional output is returned.
@@ -224,17 +389,9 @@ impl HWIClient {
         path: &DerivationPath,
         expert: bool,
     ) -> Result<HWIExtendedPubKey, Error> {
-        let prefixed_path = format!("m/{}", path);
-        Python::with_gil(|py| {
-            let func_args = (&self.hw_client, prefixed_path, expert);
-            let output = self
-                .hwilib
-                .commands
-                .getattr(py, "getxpub")?
-                .call1(py, func_args)?;
-            let output = self.hwilib.json_dumps.call1(py, (output,))?;
-            deserialize_obj!(&output.to_string())
-        })
+        let path = path.clone();
+        let output = self.call(Operation::Query, move |hwi| hwi.get_xpub(&path, expert))?;
+        deserialize_obj!(&output)
     }
 
     /// Signs a message.
@@ -243,21 +400,89 @@ impl HWIClient {
         message: &str,
         path: &DerivationPath,
     ) -> Result<HWISignature, Error> {
-        let prefixed_path = format!("m/{}", path);
-        Python::with_gil(|py| {
-            let func_args = (&self.hw_client, message, prefixed_path);
-            let output = self
-                .hwilib
-                .commands
-                .getattr(py, "signmessage")?
-                .call1(py, func_args)?;
-            let output = self.hwilib.json_dumps.call1(py, (output,))?;
-            deserialize_obj!(&output.to_string())
-        })
+        let (message, path) = (message.to_string(), path.clone());
+        let output = self.call(Operation::Confirmation, move |hwi| {
+            hwi.sign_message(&message, &path)
+        })?;
+        deserialize_obj!(&output)
+    }
+
+    /// Signs `message` following BIP 322, proving the ownership of the `address_type` address of
+    /// the key at `path`.
+    ///
+    /// The virtual `to_sign` transaction is signed with [`sign_tx`](Self::sign_tx), so the device
+    /// may show it as a transaction spending nothing. P2PKH and P2SH-P2WPKH addresses can only be
+    /// signed for in the [`Full`](Bip322Format::Full) format.
+    pub fn sign_message_bip322(
+        &self,
+        message: &str,
+        path: &DerivationPath,
+        address_type: HWIAddressType,
+        format: Bip322Format,
+    ) -> Result<String, Error> {
+        let fingerprint = self.get_master_fingerprint()?;
+        let public_key = self.get_xpub(path, false)?.public_key;
+        let key_source = (fingerprint, path.clone());
+
+        let compressed = CompressedPublicKey(public_key);
+        let internal_key = XOnlyPublicKey::from(public_key);
+        let redeem_script = ScriptBuf::new_p2wpkh(&compressed.wpubkey_hash());
+        let script_pubkey = match address_type {
+            HWIAddressType::Legacy => ScriptBuf::new_p2pkh(&compressed.pubkey_hash()),
+            HWIAddressType::Sh_Wit => ScriptBuf::new_p2sh(&redeem_script.script_hash()),
+            HWIAddressType::Wit => redeem_script.clone(),
+            HWIAddressType::Tap => {
+                ScriptBuf::new_p2tr(&Secp256k1::verification_only(), internal_key, None)
+            }
+        };
+
+        let mut psbt = bip322::to_sign_psbt(&script_pubkey, message);
+        let input = &mut psbt.inputs[0];
+        match address_type {
+            HWIAddressType::Tap => {
+                input.tap_internal_key = Some(internal_key);
+                input
+                    .tap_key_origins
+                    .insert(internal_key, (vec![], key_source));
+            }
+            HWIAddressType::Sh_Wit => {
+                input.redeem_script = Some(redeem_script);
+                input.bip32_derivation.insert(public_key, key_source);
+            }
+            HWIAddressType::Legacy | HWIAddressType::Wit => {
+                input.bip32_derivation.insert(public_key, key_source);
+            }
+        }
+
+        let signed = self.sign_tx(&psbt)?;
+        bip322::finalize(&signed.psbt, format)
+    }
+
+    /// Returns the fingerprint of the master key of the device.
+    pub fn get_master_fingerprint(&self) -> Result<Fingerprint, Error> {
+        Ok(self
+            .get_xpub(&DerivationPath::master(), false)?
+            .fingerprint())
+    }
+
+    /// Checks that `signature` of `message`, as returned by [`sign_message`](Self::sign_message),
+    /// was made by the key the device reports at `path`.
+    pub fn check_message_signature(
+        &self,
+        signature: &HWISignature,
+        message: &str,
+        path: &DerivationPath,
+    ) -> Result<bool, Error> {
+        let signer = signature.recover_pubkey(message)?;
+        let xpub = self.get_xpub(path, false)?;
+        Ok(signer.inner == xpub.public_key)
     }
 
     /// Returns an array of keys that can be imported in Bitcoin core using importmulti
     ///
+    /// Prefer [`get_keypool_with`](Self::get_keypool_with), which doesn't rely on the order of
+    /// the boolean arguments.
+    ///
     /// * `keypool` - `keypool` value in result. Check bitcoin core importmulti documentation for further information
     /// * `internal` - Whether to use internal (change) or external keys
     /// * `addr_type` - Address type to use
@@ -267,7 +492,7 @@ impl HWIClient {
     /// * `start` - Keypool start
     /// * `end` - Keypool end
     #[allow(clippy::too_many_arguments)]
-    pub fn get_keypool(
+    pub fn get_keypool<D>(
         &self,
         keypool: bool,
         internal: bool,
@@ -277,66 +502,111 @@ impl HWIClient {
         path: Option<&DerivationPath>,
         start: u32,
         end: u32,
-    ) -> Result<Vec<HWIKeyPoolElement>, Error> {
-        Python::with_gil(|py| {
-            let mut p_str = py.None();
-            if let Some(p) = path {
-                p_str = format!("m/{}/*", p).into_py(py);
-            }
-            let func_args = (
-                &self.hw_client,
-                p_str,
-                start,
-                end,
-                internal,
-                keypool,
-                account.unwrap_or(0),
-                addr_type,
-                addr_all,
-            );
-            let output = self
-                .hwilib
-                .commands
-                .getattr(py, "getkeypool")?
-                .call1(py, func_args)?;
-            let output = self.hwilib.json_dumps.call1(py, (output,))?;
-            deserialize_obj!(&output.to_string())
-        })
+    ) -> Result<Vec<HWIKeyPoolElement<D>>, Error>
+    where
+        D: ToDescriptor + DeserializeOwned,
+    {
+        let request = KeypoolRequest {
+            keypool,
+            internal,
+            addr_type,
+            addr_all,
+            account,
+            path: path.cloned(),
+            start,
+            end,
+        };
+        self.get_keypool_with(&request)
+    }
+
+    /// Returns an array of keys that can be imported in Bitcoin core using importmulti, as
+    /// described by `request`.
+    /// ```no_run
+    /// # use hwi::PythonHWIClient;
+    /// # use hwi::types::*;
+    /// # use hwi::error::Error;
+    /// # fn main() -> Result<(), Error> {
+    /// # let client = PythonHWIClient::find_device(None, None, None, false, bitcoin::Network::Testnet)?;
+    /// let request = KeypoolRequest::new(0, 20).internal(true).keypool(false);
+    /// let change_keys = client.get_keypool_with::<String>(&request)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn get_keypool_with<D>(
+        &self,
+        request: &KeypoolRequest,
+    ) -> Result<Vec<HWIKeyPoolElement<D>>, Error>
+    where
+        D: ToDescriptor + DeserializeOwned,
+    {
+        let request = request.clone();
+        let output = self.call(Operation::Query, move |hwi| hwi.get_keypool(&request))?;
+        deserialize_obj!(&output)
     }
 
     /// Returns device descriptors. You can optionally specify a BIP43 account to use.
-    pub fn get_descriptors<T>(&self, account: Option<u32>) -> Result<HWIDescriptor<T>, Error>
+    pub fn get_descriptors<D>(&self, account: Option<u32>) -> Result<HWIDescriptor<D>, Error>
     where
-        T: ToDescriptor + DeserializeOwned,
+        D: ToDescriptor + DeserializeOwned,
     {
-        Python::with_gil(|py| {
-            let func_args = (&self.hw_client, account.unwrap_or(0));
-            let output = self
-                .hwilib
-                .commands
-                .getattr(py, "getdescriptors")?
-                .call1(py, func_args)?;
-            let output = self.hwilib.json_dumps.call1(py, (output,))?;
-            deserialize_obj!(&output.to_string())
-        })
+        let output = self.call(Operation::Query, move |hwi| hwi.get_descriptors(account))?;
+        deserialize_obj!(&output)
     }
 
     /// Returns an address given a descriptor.
-    pub fn display_address_with_desc<T>(&self, descriptor: &T) -> Result<HWIAddress, Error>
+    pub fn display_address_with_desc<D>(&self, descriptor: &D) -> Result<HWIAddress, Error>
+    where
+        D: ToDescriptor + ToString,
+    {
+        let descriptor = descriptor.to_string().split('#').collect::<Vec<_>>()[0].to_string();
+        let output = self.call(Operation::Confirmation, move |hwi| {
+            hwi.display_address_with_desc(&descriptor, None)
+        })?;
+        deserialize_obj!(&output)
+    }
+
+    /// Returns an address given a descriptor belonging to a wallet policy registered with
+    /// [`register_policy`](Self::register_policy).
+    pub fn display_address_with_policy<D>(
+        &self,
+        descriptor: &D,
+        policy: &HWIWalletPolicy,
+    ) -> Result<HWIAddress, Error>
+    where
+        D: ToDescriptor + ToString,
+    {
+        let descriptor = descriptor.to_string().split('#').collect::<Vec<_>>()[0].to_string();
+        let policy = policy.clone();
+        let output = self.call(Operation::Confirmation, move |hwi| {
+            hwi.display_address_with_desc(&descriptor, Some(&policy))
+        })?;
+        deserialize_obj!(&output)
+    }
+
+    /// Registers a wallet policy, such as a multisig, on the device under `name`, after the user
+    /// approves it. Only supported by some devices (e.g. Ledger, BitBox02 and Jade).
+    ///
+    /// The returned policy holds the HMAC computed by the device, to be passed back to
+    /// [`display_address_with_policy`](Self::display_address_with_policy) and
+    /// [`sign_tx_with_policy`](Self::sign_tx_with_policy). Store it along with the wallet, as the
+    /// device doesn't keep it.
+    ///
+    /// Fails with [`Error::UnsupportedAction`] if the installed HWI predates wallet policies, as
+    /// do the calls taking a registered policy.
+    pub fn register_policy<D>(&self, name: &str, descriptor: &D) -> Result<HWIWalletPolicy, Error>
     where
-        T: ToDescriptor + ToString,
+        D: ToDescriptor + ToString,
     {
-        Python::with_gil(|py| {
-            let path = py.None();
-            let descriptor = descriptor.to_string().split('#').collect::<Vec<_>>()[0].to_string();
-            let func_args = (&self.hw_client, path, descriptor);
-            let output = self
-                .hwilib
-                .commands
-                .getattr(py, "displayaddress")?
-                .call1(py, func_args)?;
-            let output = self.hwilib.json_dumps.call1(py, (output,))?;
-            deserialize_obj!(&output.to_string())
+        let descriptor = descriptor.to_string().split('#').collect::<Vec<_>>()[0].to_string();
+        let (owned_name, owned_descriptor) = (name.to_string(), descriptor.clone());
+        let output = self.call(Operation::Confirmation, move |hwi| {
+            hwi.register_policy(&owned_name, &owned_descriptor)
+        })?;
+        let registration: HWIPolicyRegistration = deserialize_obj!(&output)?;
+        Ok(HWIWalletPolicy {
+            name: name.to_string(),
+            descriptor,
+            hmac: registration.hmac,
         })
     }
 
@@ -346,18 +616,11 @@ impl HWIClient {
         path: &DerivationPath,
         address_type: HWIAddressType,
     ) -> Result<HWIAddress, Error> {
-        Python::with_gil(|py| {
-            let prefixed_path = format!("m/{}", path);
-            let descriptor = py.None();
-            let func_args = (&self.hw_client, prefixed_path, descriptor, address_type);
-            let output = self
-                .hwilib
-                .commands
-                .getattr(py, "displayaddress")?
-                .call1(py, func_args)?;
-            let output = self.hwilib.json_dumps.call1(py, (output,))?;
-            deserialize_obj!(&output.to_string())
-        })
+        let path = path.clone();
+        let output = self.call(Operation::Confirmation, move |hwi| {
+            hwi.display_address_with_path(&path, address_type)
+        })?;
+        deserialize_obj!(&output)
     }
 
     /// Install the udev rules to the local machine.
@@ -365,78 +628,37 @@ impl HWIClient {
     /// The rules will be copied from the source to the location; the default source location is
     /// `./udev`, the default destination location is `/lib/udev/rules.d`
     pub fn install_udev_rules(source: Option<&str>, location: Option<&str>) -> Result<(), Error> {
-        Python::with_gil(|py| {
-            let libs = HWILib::initialize()?;
-            let func_args = (
-                source.unwrap_or("./udev"),
-                location.unwrap_or("/lib/udev/rules.d/"),
-            );
-            let output = libs
-                .commands
-                .getattr(py, "install_udev_rules")?
-                .call1(py, func_args)?;
-            let output = libs.json_dumps.call1(py, (output,))?;
-            let status: HWIStatus = deserialize_obj!(&output.to_string())?;
-            status.into()
-        })
+        let output = T::install_udev_rules(
+            source.unwrap_or("./udev"),
+            location.unwrap_or("/lib/udev/rules.d/"),
+        )?;
+        let status: HWIStatus = deserialize_obj!(&output)?;
+        status.into()
     }
 
     /// Set logging level
     /// # Arguments
     /// * `level` - Log level.
     pub fn set_log_level(level: LogLevel) -> Result<(), Error> {
-        Python::with_gil(|py| {
-            let arg = match level {
-                LogLevel::DEBUG => 10,
-                LogLevel::INFO => 20,
-                LogLevel::WARNING => 30,
-                LogLevel::ERROR => 40,
-                LogLevel::CRITICAL => 50,
-            };
-            py_run!(
-                py,
-                arg,
-                r#"
-                import logging
-                logging.basicConfig(level=arg)            
-                "#
-            );
-            Ok(())
-        })
+        T::set_log_level(level)
     }
 
     /// Toggle whether the device is using a BIP 39 passphrase.
     pub fn toggle_passphrase(&self) -> Result<(), Error> {
-        Python::with_gil(|py| {
-            let func_args = (&self.hw_client,);
-            let output = self
-                .hwilib
-                .commands
-                .getattr(py, "toggle_passphrase")?
-                .call1(py, func_args)?;
-            let output = self.hwilib.json_dumps.call1(py, (output,))?;
-            let status: HWIStatus = deserialize_obj!(&output.to_string())?;
-            status.into()
-        })
+        let output = self.call(Operation::Confirmation, |hwi| hwi.toggle_passphrase())?;
+        let status: HWIStatus = deserialize_obj!(&output)?;
+        status.into()
     }
 
     /// Setup a device
     pub fn setup_device(&self, label: Option<&str>, passphrase: Option<&str>) -> Result<(), Error> {
-        Python::with_gil(|py| {
-            let func_args = (
-                &self.hw_client,
-                label.unwrap_or(""),
-                passphrase.unwrap_or(""),
-            );
-            let output = self
-                .hwilib
-                .commands
-                .getattr(py, "setup_device")?
-                .call1(py, func_args)?;
-            let output = self.hwilib.json_dumps.call1(py, (output,))?;
-            let status: HWIStatus = deserialize_obj!(&output.to_string())?;
-            status.into()
-        })
+        let label = label.unwrap_or("").to_string();
+        let passphrase = passphrase.unwrap_or("").to_string();
+        let output = self.call(Operation::Confirmation, move |hwi| {
+            hwi.setup_device(&label, &passphrase)
+        })?;
+        let status: HWIStatus = deserialize_obj!(&output)?;
+        status.into()
     }
 
     /// Restore a device
@@ -445,18 +667,13 @@ impl HWIClient {
         label: Option<&str>,
         word_count: Option<HWIWordCount>,
     ) -> Result<(), Error> {
-        Python::with_gil(|py| {
-            let word_count: u8 = word_count.map_or_else(|| 24, |w| w as u8);
-            let func_args = (&self.hw_client, label.unwrap_or(""), word_count);
-            let output = self
-                .hwilib
-                .commands
-                .getattr(py, "restore_device")?
-                .call1(py, func_args)?;
-            let output = self.hwilib.json_dumps.call1(py, (output,))?;
-            let status: HWIStatus = deserialize_obj!(&output.to_string())?;
-            status.into()
-        })
+        let word_count: u8 = word_count.map_or_else(|| 24, |w| w as u8);
+        let label = label.unwrap_or("").to_string();
+        let output = self.call(Operation::Confirmation, move |hwi| {
+            hwi.restore_device(&label, word_count)
+        })?;
+        let status: HWIStatus = deserialize_obj!(&output)?;
+        status.into()
     }
 
     /// Create a backup of the device
@@ -465,69 +682,273 @@ impl HWIClient {
         label: Option<&str>,
         backup_passphrase: Option<&str>,
     ) -> Result<(), Error> {
-        Python::with_gil(|py| {
-            let func_args = (
-                &self.hw_client,
-                label.unwrap_or_default(),
-                backup_passphrase.unwrap_or_default(),
-            );
-            let output = self
-                .hwilib
-                .commands
-                .getattr(py, "backup_device")?
-                .call1(py, func_args)?;
-            let output = self.hwilib.json_dumps.call1(py, (output,))?;
-            let status: HWIStatus = deserialize_obj!(&output.to_string())?;
-            status.into()
-        })
+        let label = label.unwrap_or_default().to_string();
+        let backup_passphrase = backup_passphrase.unwrap_or_default().to_string();
+        let output = self.call(Operation::Confirmation, move |hwi| {
+            hwi.backup_device(&label, &backup_passphrase)
+        })?;
+        let status: HWIStatus = deserialize_obj!(&output)?;
+        status.into()
     }
 
     /// Wipe a device
     pub fn wipe_device(&self) -> Result<(), Error> {
-        Python::with_gil(|py| {
-            let func_args = (&self.hw_client,);
-            let output = self
-                .hwilib
-                .commands
-                .getattr(py, "wipe_device")?
-                .call1(py, func_args)?;
-            let output = self.hwilib.json_dumps.call1(py, (output,))?;
-            let status: HWIStatus = deserialize_obj!(&output.to_string())?;
-            status.into()
-        })
+        let output = self.call(Operation::Confirmation, |hwi| hwi.wipe_device())?;
+        let status: HWIStatus = deserialize_obj!(&output)?;
+        status.into()
     }
 
-    /// Get the installed version of hwilib. Returns None if hwi is not installed.
+    /// Asks a locked device to show its scrambled PIN matrix, to be followed by
+    /// [`send_pin`](Self::send_pin). This is only needed by devices without a screen for entering
+    /// the PIN, such as the Trezor One and the KeepKey, which report `needs_pin_sent`.
+    pub fn prompt_pin(&self) -> Result<(), Error> {
+        let output = self.call(Operation::Query, |hwi| hwi.prompt_pin())?;
+        let status: HWIStatus = deserialize_obj!(&output)?;
+        status.into()
+    }
+
+    /// Sends the PIN of a device after [`prompt_pin`](Self::prompt_pin).
+    ///
+    /// Each digit of `pin` is the position of the actual PIN digit in the matrix shown by the
+    /// device, numbered like a numeric keypad:
+    /// ```text
+    /// 7 8 9
+    /// 4 5 6
+    /// 1 2 3
+    /// ```
+    pub fn send_pin(&self, pin: &str) -> Result<(), Error> {
+        let pin = pin.to_string();
+        let output = self.call(Operation::Query, move |hwi| hwi.send_pin(&pin))?;
+        let status: HWIStatus = deserialize_obj!(&output)?;
+        status.into()
+    }
+
+    /// Enumerates the devices again to check whether the device at `device_path` has been
+    /// unlocked. Returns the device once it no longer needs its PIN, or `None` while it is still
+    /// locked.
+    pub fn check_unlocked(device_path: &str) -> Result<Option<HWIDevice>, Error> {
+        let device = Self::enumerate_internal(None)?
+            .into_iter()
+            .find(|d| d.path.as_deref() == Some(device_path))
+            .ok_or_else(|| {
+                Error::from_details(HWIErrorDetails {
+                    message: format!("no device at path {}", device_path),
+                    code: Some(ErrorCode::DeviceConnError),
+                    traceback: None,
+                })
+            })?;
+        if device.needs_pin_sent.unwrap_or(false) {
+            return Ok(None);
+        }
+        device.try_into().map(Some)
+    }
+
+    /// Calls [`check_unlocked`](Self::check_unlocked) every `poll_interval` until the device at
+    /// `device_path` is unlocked, giving up after `timeout`.
+    pub fn wait_for_unlock(
+        device_path: &str,
+        poll_interval: Duration,
+        timeout: Duration,
+    ) -> Result<HWIDevice, Error> {
+        let start = Instant::now();
+        loop {
+            if let Some(device) = Self::check_unlocked(device_path)? {
+                return Ok(device);
+            }
+            if start.elapsed() >= timeout {
+                return Err(Error::from_details(HWIErrorDetails {
+                    message: format!("device at path {} is still locked", device_path),
+                    code: Some(ErrorCode::DeviceNotReady),
+                    traceback: None,
+                }));
+            }
+            thread::sleep(poll_interval);
+        }
+    }
+
+    /// Get the installed version of hwilib. Returns None if hwi is not installed, or can't be run.
     pub fn get_version() -> Option<String> {
-        Python::with_gil(|py| {
-            Some(
-                PyModule::import_bound(py, "hwilib")
-                    .ok()?
-                    .getattr("__version__")
-                    .expect("Should have a __version__")
-                    .to_string(),
-            )
-        })
+        T::get_version()
     }
 
     /// Install hwi for the current user via pip. If no version is specified, the default version from pip will be installed.
     pub fn install_hwilib(version: Option<&str>) -> Result<(), Error> {
-        let hwi_with_version = match version {
-            Some(ver) => "hwi==".to_owned() + ver,
-            None => "hwi".to_owned(),
+        T::install_hwilib(version)
+    }
+}
+
+/// Builder for [`HWIClient`], selecting the device to open among the connected ones.
+///
+/// Unless a device is given with [`device`](Self::device), [`build`](Self::build) opens the first
+/// connected device matching all the filters set.
+/// ```no_run
+/// # use hwi::PythonHWIClient;
+/// # use hwi::types::*;
+/// # use hwi::error::Error;
+/// # fn main() -> Result<(), Error> {
+/// let client = PythonHWIClient::builder()
+///     .chain(bitcoin::Network::Testnet.into())
+///     .device_type(HWIDeviceType::Trezor)
+///     .passphrase(HWIPassphrase::OnDevice)
+///     .build()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct HWIClientBuilder<T: HWIImplementation> {
+    chain: HWIChain,
+    expert: bool,
+    passphrase: HWIPassphrase,
+    device: Option<HWIDevice>,
+    device_type: Option<HWIDeviceType>,
+    fingerprint: Option<Fingerprint>,
+    path: Option<String>,
+    log_level: Option<LogLevel>,
+    timeouts: HWITimeouts,
+    _implementation: PhantomData<T>,
+}
+
+impl<T: HWIImplementation> Default for HWIClientBuilder<T> {
+    fn default() -> Self {
+        HWIClientBuilder {
+            chain: bitcoin::Network::Bitcoin.into(),
+            expert: false,
+            passphrase: HWIPassphrase::Empty,
+            device: None,
+            device_type: None,
+            fingerprint: None,
+            path: None,
+            log_level: None,
+            timeouts: HWITimeouts::default(),
+            _implementation: PhantomData,
+        }
+    }
+}
+
+impl<T: HWIImplementation> HWIClientBuilder<T> {
+    /// Creates a builder for a mainnet client, opening the first connected device.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the chain the client works on.
+    pub fn chain(mut self, chain: HWIChain) -> Self {
+        self.chain = chain;
+        self
+    }
+
+    /// Enables additional output for some commands.
+    pub fn expert(mut self, expert: bool) -> Self {
+        self.expert = expert;
+        self
+    }
+
+    /// Sets the BIP 39 passphrase to open the device with. A passphrase sent by the host is also
+    /// used as the password of devices having one, including when enumerating them to find the
+    /// device.
+    pub fn passphrase(mut self, passphrase: HWIPassphrase) -> Self {
+        self.passphrase = passphrase;
+        self
+    }
+
+    /// Opens `device`, as returned by [`HWIClient::enumerate`], ignoring the other filters.
+    pub fn device(mut self, device: HWIDevice) -> Self {
+        self.device = Some(device);
+        self
+    }
+
+    /// Only opens a device of type `device_type`.
+    pub fn device_type(mut self, device_type: HWIDeviceType) -> Self {
+        self.device_type = Some(device_type);
+        self
+    }
+
+    /// Only opens the device with the master key fingerprint `fingerprint`.
+    pub fn fingerprint(mut self, fingerprint: Fingerprint) -> Self {
+        self.fingerprint = Some(fingerprint);
+        self
+    }
+
+    /// Only opens the device at `path`.
+    pub fn path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_string());
+        self
+    }
+
+    /// Sets the log level of HWI when building the client.
+    pub fn log_level(mut self, log_level: LogLevel) -> Self {
+        self.log_level = Some(log_level);
+        self
+    }
+
+    /// Sets the timeouts of the calls made through the client, see
+    /// [`HWIClient::with_timeouts`].
+    pub fn timeouts(mut self, timeouts: HWITimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    fn matches(&self, device: &HWIDevice) -> bool {
+        self.device_type
+            .as_ref()
+            .map_or(true, |t| &device.device_type == t)
+            && self.fingerprint.map_or(true, |f| device.fingerprint == f)
+            && self.path.as_ref().map_or(true, |p| &device.path == p)
+    }
+
+    /// Returns whether the device of an entry HWI reported an error for may match the filters,
+    /// the fields HWI couldn't get matching any filter.
+    fn may_match(&self, entry: &HWIDeviceInternal) -> bool {
+        let may_equal = |field: Option<String>, filter: Option<String>| match (field, filter) {
+            (Some(field), Some(filter)) => field == filter,
+            _ => true,
         };
-        let output = Command::new("pip")
-            .args(vec!["install", "--user", hwi_with_version.as_str()])
-            .output()?;
-        if output.status.success() {
-            Ok(())
-        } else {
-            Err(Error::Hwi(
-                std::str::from_utf8(&output.stderr)
-                    .expect("Non UTF-8 error while installing")
-                    .to_string(),
-                None,
-            ))
+        may_equal(
+            entry.device_type.clone(),
+            self.device_type.as_ref().map(|t| t.to_string()),
+        ) && may_equal(
+            entry.fingerprint.map(|f| f.to_string()),
+            self.fingerprint.map(|f| f.to_string()),
+        ) && may_equal(entry.path.clone(), self.path.clone())
+    }
+
+    /// Opens the device and returns its client.
+    ///
+    /// If no device matches, but HWI reported an error for a device that may, e.g. because it's
+    /// locked, that error is returned.
+    pub fn build(self) -> Result<HWIClient<T>, Error> {
+        if let Some(level) = self.log_level {
+            T::set_log_level(level)?;
+        }
+        let device = match &self.device {
+            Some(device) => device.clone(),
+            None => self.find()?,
+        };
+        let client = HWIClient::<T>::get_client_with_passphrase(
+            &device,
+            self.expert,
+            self.chain,
+            &self.passphrase,
+        )?;
+        Ok(client.with_timeouts(self.timeouts))
+    }
+
+    fn find(&self) -> Result<HWIDevice, Error> {
+        // The fingerprint of a device needing a passphrase is only known when it's sent
+        let password = match &self.passphrase {
+            HWIPassphrase::Host(passphrase) => Some(passphrase.as_str()),
+            HWIPassphrase::Empty | HWIPassphrase::OnDevice => None,
+        };
+        let mut error = None;
+        for entry in HWIClient::<T>::enumerate_internal(password)? {
+            let may_match = self.may_match(&entry);
+            match HWIDevice::try_from(entry) {
+                Ok(device) if self.matches(&device) => return Ok(device),
+                Ok(_) => {}
+                Err(e) if may_match && error.is_none() => error = Some(e),
+                Err(_) => {}
+            }
         }
+        Err(error.unwrap_or_else(|| Error::Hwi("device not found".to_string(), None)))
     }
 }
@@ -2,24 +2,31 @@
 //! - [`HWIClient`]: A Rust wrapper for the [Bitcoin Hardware Wallet Interface](https://github.com/bitcoin-core/HWI/).
 //! - [`HWISigner`]: An implementation of a [`TransactionSigner`] to be used with hardware wallets, that relies on [`HWIClient`].
 //!
+//! `HWIClient` reaches HWI through an [`HWIImplementation`](types::HWIImplementation). The
+//! `python` feature (enabled by default) provides `PythonHWI`, which embeds Python through PyO3,
+//! while the `binary` feature provides `BinaryHWI`, which runs the `hwi` executable instead. The
+//! data types in [`types`] and [`error`] are available without either of them.
+//!
+//! `PythonHWIClient` and `PythonHWISigner` name the client and the signer using `PythonHWI`.
+//!
 //! # HWIClient Example:
 //! ## Display address with path
 //! ```no_run
 //! use bitcoin::bip32::{ChildNumber, DerivationPath};
 //! use hwi::error::Error;
-//! use hwi::interface::HWIClient;
+//! use hwi::interface::PythonHWIClient;
 //! use hwi::types;
 //! use std::str::FromStr;
 //!
 //! fn main() -> Result<(), Error> {
 //!     // Find information about devices
-//!     let mut devices = HWIClient::enumerate()?;
+//!     let mut devices = PythonHWIClient::enumerate()?;
 //!     if devices.is_empty() {
 //!         panic!("No device found!");
 //!     }
 //!     let device = devices.remove(0)?;
 //!     // Create a client for a device
-//!     let client = HWIClient::get_client(&device, true, bitcoin::Network::Testnet.into())?;
+//!     let client = PythonHWIClient::get_client(&device, true, bitcoin::Network::Testnet.into())?;
 //!     // Display the address from path
 //!     let derivation_path = DerivationPath::from_str("m/44'/1'/0'/0/0").unwrap();
 //!     let hwi_address =
@@ -38,17 +45,17 @@
 //! use bdk_wallet::descriptor::Descriptor;
 //! use bdk_wallet::signer::SignerOrdering;
 //! use bdk_wallet::{KeychainKind, SignOptions, Wallet};
-//! use hwi::{HWIClient, HWISigner};
+//! use hwi::{PythonHWIClient, PythonHWISigner};
 //! use std::str::FromStr;
 //! use std::sync::Arc;
 //!
 //! fn main() -> Result<(), Box<dyn std::error::Error>> {
-//!     let mut devices = HWIClient::enumerate()?;
+//!     let mut devices = PythonHWIClient::enumerate()?;
 //!     if devices.is_empty() {
 //!         panic!("No devices found!");
 //!     }
 //!     let first_device = devices.remove(0)?;
-//!     let custom_signer = HWISigner::from_device(&first_device, Network::Testnet.into())?;
+//!     let custom_signer = PythonHWISigner::from_device(&first_device, Network::Testnet.into())?;
 //!
 //!     let mut wallet = Wallet::create("", "")
 //!         .network(Network::Testnet)
@@ -74,22 +81,39 @@
 extern crate serial_test;
 extern crate core;
 
-pub use interface::HWIClient;
+#[cfg(feature = "async")]
+pub use async_interface::AsyncHWIClient;
+pub use coordinator::{PsbtCoordinator, SigningReport};
+#[cfg(feature = "python")]
+pub use interface::PythonHWIClient;
+pub use interface::{HWICancelHandle, HWIClient, HWIClientBuilder};
+pub use shared_interface::{LockPolicy, SharedHWIClient};
 #[cfg(feature = "signer")]
 pub use signer::HWISigner;
+#[cfg(all(feature = "signer", feature = "python"))]
+pub use signer::PythonHWISigner;
+pub use watcher::{DeviceEvent, DeviceWatcher};
 
+#[cfg(feature = "async")]
+pub mod async_interface;
+pub mod bip322;
+pub mod coordinator;
 #[cfg(feature = "doctest")]
 pub mod doctest;
 pub mod error;
+pub mod implementations;
 pub mod interface;
+mod psbt_utils;
+pub mod shared_interface;
 #[cfg(feature = "signer")]
 pub mod signer;
 pub mod types;
+pub mod watcher;
 
-#[cfg(test)]
+#[cfg(all(test, feature = "python"))]
 mod tests {
     use crate::types::{self, HWIDeviceType, TESTNET};
-    use crate::HWIClient;
+    use crate::PythonHWIClient as HWIClient;
     use std::collections::BTreeMap;
     use std::str::FromStr;
 
@@ -316,7 +340,7 @@ mod tests {
         let start = 1;
         let end = 5;
         client
-            .get_keypool(
+            .get_keypool::<String>(
                 keypool,
                 internal,
                 address_type,
@@ -335,7 +359,7 @@ mod tests {
         let start = 1;
         let end = 8;
         client
-            .get_keypool(
+            .get_keypool::<String>(
                 keypool,
                 internal,
                 address_type,
@@ -354,7 +378,7 @@ mod tests {
         let start = 0;
         let end = 10;
         client
-            .get_keypool(
+            .get_keypool::<String>(
                 keypool,
                 internal,
                 address_type,
@@ -489,3 +513,2050 @@ mod tests {
         HWIClient::install_hwilib(Some("2.1.1")).unwrap();
     }
 }
+
+#[cfg(all(test, feature = "binary"))]
+mod binary_tests {
+    use crate::error::{Error, ErrorCode};
+    use crate::implementations::binary_implementation::{
+        global_args, resolve_binary_path, BinaryHWI, HWIBinaryExecutor,
+    };
+    use crate::types::{HWIDevice, HWIDeviceType, HWIPassphrase, TESTNET};
+    use crate::HWIClient;
+    use std::path::PathBuf;
+    use std::str::FromStr;
+    use std::sync::Mutex;
+
+    use bitcoin::bip32::DerivationPath;
+    use bitcoin::Network;
+
+    static BINARY_CALLS: Mutex<Vec<(Vec<String>, Option<String>)>> = Mutex::new(Vec::new());
+
+    /// Pretends to be the `hwi` binary, recording the arguments and stdin it is called with.
+    ///
+    /// The device at `hid:passphrase` needs the passphrase `secret` to be sent through stdin, also
+    /// to enumerate it with its fingerprint.
+    #[derive(Debug)]
+    struct FakeExecutor;
+
+    impl HWIBinaryExecutor for FakeExecutor {
+        fn password_from_stdin() -> bool {
+            true
+        }
+
+        fn execute_command(
+            args: Vec<String>,
+            stdin: Option<&str>,
+        ) -> Result<String, crate::error::Error> {
+            let output = if args.iter().any(|a| a == "enumerate") && stdin == Some("secret") {
+                r#"[{"type": "trezor", "model": "trezor_t", "path": "udp:127.0.0.1:21324", "needs_pin_sent": false, "needs_passphrase_sent": false, "fingerprint": "95d8f670"}, {"type": "ledger", "path": "tcp:127.0.0.1:9999", "error": "Could not open client or get fingerprint information", "code": -13}, {"type": "trezor", "model": "trezor_1", "path": "hid:passphrase", "needs_pin_sent": false, "needs_passphrase_sent": true, "fingerprint": "1e2d3c4b"}]"#
+            } else if args.iter().any(|a| a == "enumerate") {
+                r#"[{"type": "trezor", "model": "trezor_t", "path": "udp:127.0.0.1:21324", "needs_pin_sent": false, "needs_passphrase_sent": false, "fingerprint": "95d8f670"}, {"type": "ledger", "path": "tcp:127.0.0.1:9999", "error": "Could not open client or get fingerprint information", "code": -13}, {"type": "trezor", "model": "trezor_1", "path": "hid:passphrase", "needs_pin_sent": false, "needs_passphrase_sent": true, "error": "Passphrase needs to be specified before the fingerprint information can be retrieved", "code": -12}]"#
+            } else if args.iter().any(|a| a == "hid:passphrase") && stdin != Some("secret") {
+                r#"{"error": "Passphrase needs to be specified before the fingerprint information can be retrieved", "code": -12}"#
+            } else if args.iter().any(|a| a == "signmessage") {
+                r#"{"error": "Sign message canceled by user", "code": -14}"#
+            } else if args.iter().any(|a| a == "--version") {
+                "hwi 2.3.1\n"
+            } else if args.iter().any(|a| a == "register") {
+                // Versions of HWI without wallet policies
+                r#"{"error": "hwi: error: argument command: invalid choice: 'register' (choose from 'enumerate', 'getmasterxpub', 'signtx')", "code": -13}"#
+            } else if args.iter().any(|a| a == "getxpub") {
+                r#"{"xpub": "tpubDCBWBScQPGv4Xk3JSbhw6wYYpayMjb2eAYyArpbSqQTbLDpphHGAetB6VQgVeftLML8vDSUEWcC2xDi3qJJ3YCDChJDvqVzpgoYSuT52MhJ"}"#
+            } else {
+                r#"{"success": true}"#
+            };
+            BINARY_CALLS
+                .lock()
+                .unwrap()
+                .push((args, stdin.map(String::from)));
+            Ok(output.to_string())
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_binary_enumerate() {
+        let devices = HWIClient::<BinaryHWI<FakeExecutor>>::enumerate().unwrap();
+        assert_eq!(devices.len(), 3);
+        let device = devices[0].as_ref().unwrap();
+        assert_eq!(device.device_type, HWIDeviceType::Trezor);
+        assert_eq!(device.fingerprint.to_string(), "95d8f670");
+        assert!(devices[1].is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn test_binary_get_xpub_args() {
+        BINARY_CALLS.lock().unwrap().clear();
+        let device: HWIDevice = HWIClient::<BinaryHWI<FakeExecutor>>::enumerate()
+            .unwrap()
+            .remove(0)
+            .unwrap();
+        let client =
+            HWIClient::<BinaryHWI<FakeExecutor>>::get_client(&device, false, TESTNET).unwrap();
+        let derivation_path = DerivationPath::from_str("m/44'/1'/0'").unwrap();
+        client.get_xpub(&derivation_path, false).unwrap();
+
+        let calls = BINARY_CALLS.lock().unwrap();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(
+            calls[1].0,
+            vec![
+                "--chain",
+                "test",
+                "--device-type",
+                "trezor",
+                "--device-path",
+                "udp:127.0.0.1:21324",
+                "getxpub",
+                "m/44'/1'/0'",
+            ]
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_binary_error_response() {
+        let device: HWIDevice = HWIClient::<BinaryHWI<FakeExecutor>>::enumerate()
+            .unwrap()
+            .remove(0)
+            .unwrap();
+        let client =
+            HWIClient::<BinaryHWI<FakeExecutor>>::get_client(&device, false, TESTNET).unwrap();
+        let derivation_path = DerivationPath::from_str("m/44'/1'/0'/0/0").unwrap();
+        match client.sign_message("Hello", &derivation_path) {
+            Err(Error::ActionCanceled(details)) => {
+                assert_eq!(details.message, "Sign message canceled by user");
+                assert_eq!(details.code, Some(ErrorCode::ActionCanceled));
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_binary_register_unsupported() {
+        let device: HWIDevice = HWIClient::<BinaryHWI<FakeExecutor>>::enumerate()
+            .unwrap()
+            .remove(0)
+            .unwrap();
+        let client =
+            HWIClient::<BinaryHWI<FakeExecutor>>::get_client(&device, false, TESTNET).unwrap();
+        let descriptor = "wsh(sortedmulti(2,[95d8f670/48'/1'/0'/2']tpubDCBWBScQPGv4Xk3JSbhw6wYYpayMjb2eAYyArpbSqQTbLDpphHGAetB6VQgVeftLML8vDSUEWcC2xDi3qJJ3YCDChJDvqVzpgoYSuT52MhJ/**,[95d8f670/48'/1'/1'/2']tpubDCBWBScQPGv4Xk3JSbhw6wYYpayMjb2eAYyArpbSqQTbLDpphHGAetB6VQgVeftLML8vDSUEWcC2xDi3qJJ3YCDChJDvqVzpgoYSuT52MhJ/**))".to_string();
+        match client.register_policy("multisig", &descriptor) {
+            Err(Error::UnsupportedAction(details)) => {
+                assert_eq!(details.code, Some(ErrorCode::NotImplemented))
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_binary_find_device() {
+        let client = HWIClient::<BinaryHWI<FakeExecutor>>::find_device(
+            Some("password"),
+            Some(HWIDeviceType::Trezor),
+            None,
+            true,
+            Network::Testnet,
+        )
+        .unwrap();
+        BINARY_CALLS.lock().unwrap().clear();
+        client.wipe_device().unwrap();
+        let calls = BINARY_CALLS.lock().unwrap().clone();
+        assert!(calls[0].0.ends_with(&[
+            "--stdinpass".to_string(),
+            "--expert".to_string(),
+            "wipe".to_string(),
+        ]));
+        assert_eq!(calls[0].1.as_deref(), Some("password"));
+
+        let not_found = HWIClient::<BinaryHWI<FakeExecutor>>::find_device(
+            None,
+            Some(HWIDeviceType::Ledger),
+            None,
+            false,
+            Network::Testnet,
+        );
+        assert!(not_found.is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn test_binary_unsupported_options() {
+        let device: HWIDevice = HWIClient::<BinaryHWI<FakeExecutor>>::enumerate()
+            .unwrap()
+            .remove(0)
+            .unwrap();
+        assert!(matches!(
+            HWIClient::<BinaryHWI<FakeExecutor>>::get_client(
+                &device,
+                false,
+                Network::Testnet4.into()
+            ),
+            Err(Error::InvalidValue(_))
+        ));
+        assert!(matches!(
+            HWIClient::<BinaryHWI<FakeExecutor>>::get_client_with_passphrase(
+                &device,
+                false,
+                TESTNET,
+                &HWIPassphrase::OnDevice
+            ),
+            Err(Error::UnsupportedAction(_))
+        ));
+    }
+
+    #[test]
+    #[serial]
+    fn test_binary_host_passphrase() {
+        // Without the passphrase, HWI can't get the fingerprint of the device
+        let without = HWIClient::<BinaryHWI<FakeExecutor>>::enumerate()
+            .unwrap()
+            .remove(2);
+        assert!(matches!(without, Err(Error::DeviceNotReady(_))));
+
+        BINARY_CALLS.lock().unwrap().clear();
+        let device: HWIDevice =
+            HWIClient::<BinaryHWI<FakeExecutor>>::enumerate_with_password("secret")
+                .unwrap()
+                .remove(2)
+                .unwrap();
+        assert!(device.needs_passphrase_sent);
+        assert_eq!(device.fingerprint.to_string(), "1e2d3c4b");
+        assert_eq!(
+            BINARY_CALLS.lock().unwrap()[0],
+            (
+                vec!["--stdinpass".to_string(), "enumerate".to_string()],
+                Some("secret".to_string())
+            )
+        );
+        let derivation_path = DerivationPath::from_str("m/44'/1'/0'").unwrap();
+
+        let without =
+            HWIClient::<BinaryHWI<FakeExecutor>>::get_client(&device, false, TESTNET).unwrap();
+        assert!(without.get_xpub(&derivation_path, false).is_err());
+
+        let client = HWIClient::<BinaryHWI<FakeExecutor>>::get_client_with_passphrase(
+            &device,
+            false,
+            TESTNET,
+            &HWIPassphrase::Host("secret".to_string()),
+        )
+        .unwrap();
+        BINARY_CALLS.lock().unwrap().clear();
+        client.get_xpub(&derivation_path, false).unwrap();
+
+        let calls = BINARY_CALLS.lock().unwrap();
+        assert_eq!(
+            calls[0].0,
+            vec![
+                "--chain",
+                "test",
+                "--device-type",
+                "trezor",
+                "--device-path",
+                "hid:passphrase",
+                "--stdinpass",
+                "getxpub",
+                "m/44'/1'/0'",
+            ]
+        );
+        assert_eq!(calls[0].1.as_deref(), Some("secret"));
+    }
+
+    #[test]
+    fn test_binary_password_fallback() {
+        // Without stdin, e.g. when HWI would prompt on the terminal, the password is an argument
+        assert_eq!(
+            global_args(None, Some("secret"), false, false, None).unwrap(),
+            vec!["--password", "secret"]
+        );
+        assert_eq!(
+            global_args(None, Some("secret"), true, false, None).unwrap(),
+            vec!["--stdinpass"]
+        );
+    }
+
+    #[test]
+    fn test_binary_path_override() {
+        assert_eq!(resolve_binary_path(None), PathBuf::from("hwi"));
+        assert_eq!(
+            resolve_binary_path(Some("/opt/hwi/bin/hwi".into())),
+            PathBuf::from("/opt/hwi/bin/hwi")
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_binary_get_version() {
+        assert_eq!(
+            HWIClient::<BinaryHWI<FakeExecutor>>::get_version().as_deref(),
+            Some("2.3.1")
+        );
+    }
+
+    /// Pretends to be the `hwi` binary run under another name, or failing to start.
+    #[derive(Debug)]
+    struct RenamedExecutor;
+
+    static RENAMED_VERSION_OUTPUT: Mutex<&str> = Mutex::new("");
+
+    impl HWIBinaryExecutor for RenamedExecutor {
+        fn execute_command(
+            _args: Vec<String>,
+            _stdin: Option<&str>,
+        ) -> Result<String, crate::error::Error> {
+            Ok(RENAMED_VERSION_OUTPUT.lock().unwrap().to_string())
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_binary_get_version_renamed() {
+        *RENAMED_VERSION_OUTPUT.lock().unwrap() = "hwi-2.3.1-linux-x86_64 2.4.0rc1\n";
+        assert_eq!(
+            HWIClient::<BinaryHWI<RenamedExecutor>>::get_version().as_deref(),
+            Some("2.4.0rc1")
+        );
+
+        *RENAMED_VERSION_OUTPUT.lock().unwrap() = "ModuleNotFoundError: No module named 'hwilib'\n";
+        assert_eq!(HWIClient::<BinaryHWI<RenamedExecutor>>::get_version(), None);
+    }
+}
+
+#[cfg(test)]
+mod mock_tests {
+    use crate::bip322::{self, Bip322Error, Bip322Format};
+    use crate::error::{Error, ErrorCode};
+    use crate::implementations::mock_implementation::{descriptor_checksum, MockDevice, MockHWI};
+    use crate::types::{
+        HWIAddressType, HWIDeviceType, HWIKeyPoolElement, HWIPassphrase, HWITimestamp,
+        HWIWalletPolicy, KeypoolRequest, TESTNET,
+    };
+    use crate::HWIClient;
+    use std::collections::BTreeMap;
+    use std::str::FromStr;
+
+    use bitcoin::bip32::{DerivationPath, KeySource, Xpriv, Xpub};
+    use bitcoin::locktime::absolute;
+    use bitcoin::psbt::Input;
+    use bitcoin::secp256k1::{self, Secp256k1};
+    use bitcoin::sign_message::{signed_msg_hash, MessageSignature};
+    use bitcoin::{transaction, Address, Amount, CompressedPublicKey, Network, NetworkKind};
+    use bitcoin::{OutPoint, Psbt, Transaction, TxIn, TxOut};
+
+    const SEED: [u8; 32] = [7; 32];
+
+    fn connect_mock_device() -> (MockDevice, HWIClient<MockHWI>) {
+        let device = MockDevice::from_seed(&SEED).unwrap();
+        let hwi_device = MockHWI::connect_only(device.clone());
+        let client = HWIClient::<MockHWI>::get_client(&hwi_device, false, TESTNET).unwrap();
+        (device, client)
+    }
+
+    fn derive_pubkey(path: &DerivationPath) -> secp256k1::PublicKey {
+        let secp = Secp256k1::new();
+        let xprv = Xpriv::new_master(NetworkKind::Test, &SEED).unwrap();
+        Xpub::from_priv(&secp, &xprv.derive_priv(&secp, path).unwrap()).public_key
+    }
+
+    #[test]
+    fn test_descriptor_checksum() {
+        // Test vector from BIP 380
+        assert_eq!(descriptor_checksum("raw(deadbeef)").unwrap(), "89f8spxm");
+    }
+
+    #[test]
+    #[serial]
+    fn test_mock_enumerate() {
+        MockHWI::disconnect_all();
+        let device = MockDevice::from_seed(&SEED)
+            .unwrap()
+            .with_model(HWIDeviceType::Ledger, "ledger_nano_s_plus");
+        let locked = MockDevice::from_seed(&[8; 32])
+            .unwrap()
+            .with_needs_pin_sent(true);
+        MockHWI::connect(device.clone());
+        MockHWI::connect(locked);
+
+        let devices = HWIClient::<MockHWI>::enumerate().unwrap();
+        assert_eq!(devices.len(), 2);
+        let first = devices[0].as_ref().unwrap();
+        assert_eq!(first.device_type, HWIDeviceType::Ledger);
+        assert_eq!(first.fingerprint, device.fingerprint());
+        match &devices[1] {
+            Err(Error::DeviceNotReady(details)) => {
+                assert_eq!(details.code, Some(ErrorCode::DeviceNotReady))
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_mock_get_master_xpub() {
+        let (device, client) = connect_mock_device();
+        let xpub = client.get_master_xpub(HWIAddressType::Wit, 2).unwrap();
+        let path = DerivationPath::from_str("m/84'/1'/2'").unwrap();
+        assert_eq!(xpub.public_key, derive_pubkey(&path));
+        assert_eq!(xpub.network, NetworkKind::Test);
+        assert_eq!(xpub.depth, 3);
+
+        let descriptors = client.get_descriptors::<String>(Some(2)).unwrap();
+        assert_eq!(descriptors.receive.len(), 4);
+        assert!(descriptors.receive[2].starts_with(&format!(
+            "wpkh([{}/84'/1'/2']{}/0/*)#",
+            device.fingerprint(),
+            xpub.xpub
+        )));
+    }
+
+    #[test]
+    #[serial]
+    #[cfg(feature = "miniscript")]
+    fn test_mock_get_miniscript_descriptors() {
+        use miniscript::{Descriptor, DescriptorPublicKey};
+
+        let (_, client) = connect_mock_device();
+        let descriptors = client
+            .get_descriptors::<Descriptor<DescriptorPublicKey>>(None)
+            .unwrap();
+        assert_eq!(descriptors.internal.len(), 4);
+    }
+
+    #[test]
+    #[serial]
+    #[cfg(feature = "miniscript")]
+    fn test_mock_get_miniscript_keypool() {
+        use miniscript::{Descriptor, DescriptorPublicKey};
+
+        let (_, client) = connect_mock_device();
+        let request = KeypoolRequest::new(0, 3).addr_all(true);
+        let keypool = client
+            .get_keypool_with::<Descriptor<DescriptorPublicKey>>(&request)
+            .unwrap();
+        assert_eq!(keypool.len(), 4);
+        assert!(matches!(keypool[3].desc, Descriptor::Tr(_)));
+    }
+
+    #[test]
+    fn test_keypool_element_serde() {
+        let json = r#"{"desc": "wpkh(02c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5)", "range": [2, 7], "timestamp": 1700000000, "internal": false, "keypool": true, "watchonly": true}"#;
+        let element: HWIKeyPoolElement = serde_json::from_str(json).unwrap();
+        assert_eq!(element.range, 2..=7);
+        assert_eq!(element.timestamp, HWITimestamp::Time(1700000000));
+
+        let value = serde_json::to_value(&element).unwrap();
+        assert_eq!(value["range"], serde_json::json!([2, 7]));
+        assert_eq!(value["timestamp"], serde_json::json!(1700000000));
+        assert!(serde_json::from_str::<HWITimestamp>(r#""later""#).is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn test_mock_display_address_with_path() {
+        let (_, client) = connect_mock_device();
+        let path = DerivationPath::from_str("m/84'/1'/0'/0/0").unwrap();
+        let address = client
+            .display_address_with_path(&path, HWIAddressType::Wit)
+            .unwrap();
+        let expected =
+            Address::p2wpkh(&CompressedPublicKey(derive_pubkey(&path)), Network::Testnet);
+        assert_eq!(address.address.assume_checked(), expected);
+    }
+
+    #[test]
+    #[serial]
+    fn test_mock_sign_message() {
+        let (_, client) = connect_mock_device();
+        let path = DerivationPath::from_str("m/44'/1'/0'/0/0").unwrap();
+        let message = "I love BDK wallet";
+        let signature = client.sign_message(message, &path).unwrap();
+
+        let secp = Secp256k1::new();
+        let signature = MessageSignature::from_slice(&signature).unwrap();
+        let address = Address::p2pkh(CompressedPublicKey(derive_pubkey(&path)), Network::Testnet);
+        assert!(signature
+            .is_signed_by_address(&secp, &address, signed_msg_hash(message))
+            .unwrap());
+    }
+
+    #[test]
+    #[serial]
+    fn test_mock_verify_message() {
+        let (_, client) = connect_mock_device();
+        let path = DerivationPath::from_str("m/84'/1'/0'/0/0").unwrap();
+        let message = "I love BDK wallet";
+        let signature = client.sign_message(message, &path).unwrap();
+
+        let typed = signature.message_signature().unwrap();
+        assert!(typed.compressed);
+        let public_key = CompressedPublicKey(derive_pubkey(&path));
+        assert_eq!(
+            signature.recover_pubkey(message).unwrap(),
+            public_key.into()
+        );
+
+        let p2wpkh = Address::p2wpkh(&public_key, Network::Testnet);
+        let p2pkh = Address::p2pkh(public_key, Network::Testnet);
+        assert!(signature.verify_message(&p2wpkh, message).unwrap());
+        assert!(signature.verify_message(&p2pkh, message).unwrap());
+        assert!(!signature.verify_message(&p2wpkh, "I love BDK").unwrap());
+
+        assert!(client
+            .check_message_signature(&signature, message, &path)
+            .unwrap());
+        let other_path = DerivationPath::from_str("m/84'/1'/0'/0/1").unwrap();
+        assert!(!client
+            .check_message_signature(&signature, message, &other_path)
+            .unwrap());
+    }
+
+    #[test]
+    fn test_bip322_vectors() {
+        assert_eq!(
+            bip322::message_hash("").to_string(),
+            "c90c269c4f8fcbe6880f72a721ddfbf1914268a794cbb21cfafee13770ae19f1"
+        );
+        assert_eq!(
+            bip322::message_hash("Hello World").to_string(),
+            "f0eb03b1a75ac6d9847f55c624a99169b5dccba2a31f5b23bea77ba270de0a7a"
+        );
+
+        let address = Address::from_str("bc1q9vza2e8x573nczrlzms0wvx3gsqjx7vavgkx0l")
+            .unwrap()
+            .assume_checked();
+        let signature = "AkcwRAIgZRfIY3p7/DoVTty6YZbWS71bc5Vct9p9Fia83eRmw2QCICK/ENGfwLtptFluMGs2KsqoNSk89pO7F29zJLUx9a/sASECx/EgAxlkQpQ9hYjgGu6EBCPMVPwVIVJqO4XCsMvViHI=";
+        assert!(bip322::verify(&address, "Hello World", signature).unwrap());
+        assert!(!bip322::verify(&address, "", signature).unwrap());
+    }
+
+    #[test]
+    #[serial]
+    fn test_mock_sign_message_bip322() {
+        let (_, client) = connect_mock_device();
+        let message = "I love BDK wallet";
+        let cases = [
+            (
+                "m/44'/1'/0'/0/0",
+                HWIAddressType::Legacy,
+                Bip322Format::Full,
+            ),
+            (
+                "m/49'/1'/0'/0/0",
+                HWIAddressType::Sh_Wit,
+                Bip322Format::Full,
+            ),
+            ("m/84'/1'/0'/0/0", HWIAddressType::Wit, Bip322Format::Simple),
+            ("m/84'/1'/0'/0/0", HWIAddressType::Wit, Bip322Format::Full),
+            ("m/86'/1'/0'/0/0", HWIAddressType::Tap, Bip322Format::Simple),
+        ];
+        for (path, address_type, format) in cases {
+            let path = DerivationPath::from_str(path).unwrap();
+            let signature = client
+                .sign_message_bip322(message, &path, address_type.clone(), format)
+                .unwrap();
+            let address = client
+                .display_address_with_path(&path, address_type)
+                .unwrap()
+                .address
+                .assume_checked();
+            assert!(bip322::verify(&address, message, &signature).unwrap());
+            assert!(!bip322::verify(&address, "I love BDK", &signature).unwrap());
+        }
+
+        let path = DerivationPath::from_str("m/44'/1'/0'/0/0").unwrap();
+        assert!(matches!(
+            client.sign_message_bip322(
+                message,
+                &path,
+                HWIAddressType::Legacy,
+                Bip322Format::Simple
+            ),
+            Err(Error::Bip322(Bip322Error::UnsupportedAddress))
+        ));
+    }
+
+    /// Returns a PSBT spending a P2WPKH output of the mock device, and the key signing it.
+    fn p2wpkh_psbt(device: &MockDevice) -> (Psbt, secp256k1::PublicKey) {
+        let path = DerivationPath::from_str("m/84'/1'/0'/0/0").unwrap();
+        let public_key = derive_pubkey(&path);
+        let script_pubkey =
+            Address::p2wpkh(&CompressedPublicKey(public_key), Network::Testnet).script_pubkey();
+
+        let mut bip32_derivation: BTreeMap<secp256k1::PublicKey, KeySource> = BTreeMap::new();
+        bip32_derivation.insert(public_key, (device.fingerprint(), path));
+        let unsigned_tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(90_000),
+                script_pubkey: script_pubkey.clone(),
+            }],
+        };
+        let mut psbt = Psbt::from_unsigned_tx(unsigned_tx).unwrap();
+        psbt.inputs[0] = Input {
+            witness_utxo: Some(TxOut {
+                value: Amount::from_sat(100_000),
+                script_pubkey,
+            }),
+            bip32_derivation,
+            ..Default::default()
+        };
+        (psbt, public_key)
+    }
+
+    #[test]
+    #[serial]
+    fn test_mock_sign_tx() {
+        let (device, client) = connect_mock_device();
+        let (psbt, public_key) = p2wpkh_psbt(&device);
+
+        let signed = client.sign_tx(&psbt).unwrap();
+        let signature = signed.psbt.inputs[0].partial_sigs.values().next().unwrap();
+        let secp = Secp256k1::verification_only();
+        let (msg, _) = signed
+            .psbt
+            .sighash_ecdsa(
+                0,
+                &mut bitcoin::sighash::SighashCache::new(&psbt.unsigned_tx),
+            )
+            .unwrap();
+        secp.verify_ecdsa(&msg, &signature.signature, &public_key)
+            .unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_mock_register_policy() {
+        let (device, client) = connect_mock_device();
+        let descriptor = "wsh(sortedmulti(2,[f00dbabe/48'/1'/0'/2']tpubDCBWBScQPGv4Xk3JSbhw6wYYpayMjb2eAYyArpbSqQTbLDpphHGAetB6VQgVeftLML8vDSUEWcC2xDi3qJJ3YCDChJDvqVzpgoYSuT52MhJ/**))".to_string();
+        let policy = client
+            .register_policy("vault", &format!("{}#abcdefgh", descriptor))
+            .unwrap();
+        assert_eq!(policy.name, "vault");
+        assert_eq!(policy.descriptor, descriptor);
+        assert_eq!(policy.hmac.len(), 32);
+
+        let (psbt, _) = p2wpkh_psbt(&device);
+        let signed = client.sign_tx_with_policy(&psbt, &policy).unwrap();
+        assert_eq!(signed.psbt.inputs[0].partial_sigs.len(), 1);
+
+        let forged = HWIWalletPolicy {
+            hmac: vec![0; 32],
+            ..policy
+        };
+        match client.sign_tx_with_policy(&psbt, &forged) {
+            Err(Error::HwiException(details)) => {
+                assert_eq!(details.code, Some(ErrorCode::BadArgument))
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_mock_unlock_with_pin() {
+        MockHWI::disconnect_all();
+        let device = MockDevice::from_seed(&SEED).unwrap().with_pin("1234");
+        MockHWI::connect(device.clone());
+        assert_eq!(
+            HWIClient::<MockHWI>::check_unlocked(device.path()).unwrap(),
+            None
+        );
+        assert!(matches!(
+            HWIClient::<MockHWI>::check_unlocked("mock:nowhere"),
+            Err(Error::DeviceConnection(_))
+        ));
+        assert!(matches!(
+            HWIClient::<MockHWI>::wait_for_unlock(
+                device.path(),
+                std::time::Duration::from_millis(10),
+                std::time::Duration::from_millis(30),
+            ),
+            Err(Error::DeviceNotReady(_))
+        ));
+
+        let hwi_device = crate::types::HWIDevice {
+            device_type: HWIDeviceType::Trezor,
+            model: "mock".to_string(),
+            path: device.path().to_string(),
+            needs_pin_sent: true,
+            needs_passphrase_sent: false,
+            fingerprint: Default::default(),
+        };
+        let client = HWIClient::<MockHWI>::get_client(&hwi_device, false, TESTNET).unwrap();
+        client.prompt_pin().unwrap();
+        assert!(client.send_pin("4321").is_err());
+        client.prompt_pin().unwrap();
+        client.send_pin("1234").unwrap();
+
+        let unlocked = HWIClient::<MockHWI>::wait_for_unlock(
+            device.path(),
+            std::time::Duration::from_millis(10),
+            std::time::Duration::from_secs(1),
+        )
+        .unwrap();
+        assert_eq!(unlocked.fingerprint, device.fingerprint());
+        match client.prompt_pin() {
+            Err(Error::HwiException(details)) => {
+                assert_eq!(details.code, Some(ErrorCode::DeviceAlreadyUnlocked))
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_mock_get_client_with_passphrase() {
+        MockHWI::disconnect_all();
+        MockHWI::connect(
+            MockDevice::from_seed(&SEED)
+                .unwrap()
+                .with_needs_passphrase_sent(true),
+        );
+        // The fingerprint is only known once the passphrase is sent
+        match HWIClient::<MockHWI>::enumerate().unwrap().remove(0) {
+            Err(Error::DeviceNotReady(_)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        let hwi_device = HWIClient::<MockHWI>::enumerate_with_password("correct horse")
+            .unwrap()
+            .remove(0)
+            .unwrap();
+        assert!(hwi_device.needs_passphrase_sent);
+
+        let client = HWIClient::<MockHWI>::get_client(&hwi_device, false, TESTNET).unwrap();
+        match client.get_master_xpub(HWIAddressType::Wit, 0) {
+            Err(Error::DeviceNotReady(_)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        let client = HWIClient::<MockHWI>::get_client_with_passphrase(
+            &hwi_device,
+            false,
+            TESTNET,
+            &HWIPassphrase::Host("correct horse".to_string()),
+        )
+        .unwrap();
+        assert!(client.get_master_xpub(HWIAddressType::Wit, 0).is_ok());
+    }
+
+    #[test]
+    #[serial]
+    fn test_mock_builder() {
+        MockHWI::disconnect_all();
+        let first = MockDevice::from_seed(&SEED).unwrap();
+        let second = MockDevice::from_seed(&[8; 32])
+            .unwrap()
+            .with_model(HWIDeviceType::Ledger, "ledger_nano_x");
+        MockHWI::connect(first);
+        MockHWI::connect(second.clone());
+
+        let client = HWIClient::<MockHWI>::builder()
+            .chain(TESTNET)
+            .fingerprint(second.fingerprint())
+            .build()
+            .unwrap();
+        let request = KeypoolRequest::new(0, 9)
+            .internal(true)
+            .keypool(false)
+            .addr_type(HWIAddressType::Tap);
+        let keypool = client.get_keypool_with::<String>(&request).unwrap();
+        assert_eq!(keypool.len(), 1);
+        assert_eq!(keypool[0].range, 0..=9);
+        assert_eq!(keypool[0].timestamp, HWITimestamp::Now);
+        assert!(keypool[0].internal);
+        assert!(!keypool[0].keypool);
+        assert!(keypool[0]
+            .desc
+            .starts_with(&format!("tr([{}/86'/1'/0']", second.fingerprint())));
+
+        let missing = HWIClient::<MockHWI>::builder()
+            .device_type(HWIDeviceType::Ledger)
+            .path("mock:nowhere")
+            .build();
+        assert!(missing.is_err());
+
+        // A locked device may be the one asked for
+        MockHWI::connect(
+            MockDevice::from_seed(&[9; 32])
+                .unwrap()
+                .with_path("mock:locked")
+                .with_needs_pin_sent(true),
+        );
+        let locked = HWIClient::<MockHWI>::builder().path("mock:locked").build();
+        assert!(matches!(locked, Err(Error::DeviceNotReady(_))));
+        let missing = HWIClient::<MockHWI>::builder().path("mock:nowhere").build();
+        assert!(matches!(missing, Err(Error::Hwi(_, None))));
+    }
+
+    #[test]
+    #[serial]
+    fn test_mock_canned_error() {
+        MockHWI::disconnect_all();
+        let device = MockDevice::from_seed(&SEED).unwrap().with_error(
+            "signmessage",
+            ErrorCode::ActionCanceled,
+            "Sign message canceled by user",
+        );
+        MockHWI::connect(device.clone());
+        let client = HWIClient::<MockHWI>::find_device(
+            None,
+            None,
+            Some(&device.fingerprint().to_string()),
+            false,
+            Network::Testnet,
+        )
+        .unwrap();
+        let path = DerivationPath::from_str("m/44'/1'/0'/0/0").unwrap();
+        match client.sign_message("Hello", &path) {
+            Err(Error::ActionCanceled(details)) => {
+                assert_eq!(details.code, Some(ErrorCode::ActionCanceled))
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod error_tests {
+    use crate::error::{Error, ErrorCode, HWIErrorDetails};
+    use crate::types::{HWIDevice, HWIDeviceInternal, HWIErrorResponse};
+    use std::convert::TryFrom;
+
+    use serde_json::json;
+
+    #[test]
+    fn test_error_from_details() {
+        let details = |code| HWIErrorDetails {
+            message: "message".to_string(),
+            code,
+            traceback: None,
+        };
+        let canceled = Error::from_details(details(Some(ErrorCode::ActionCanceled)));
+        assert!(matches!(canceled, Error::ActionCanceled(_)));
+        assert_eq!(canceled.code(), Some(ErrorCode::ActionCanceled));
+        assert!(matches!(
+            Error::from_details(details(Some(ErrorCode::DeviceConnError))),
+            Error::DeviceConnection(_)
+        ));
+        assert!(matches!(
+            Error::from_details(details(Some(ErrorCode::NotImplemented))),
+            Error::UnsupportedAction(_)
+        ));
+        assert!(matches!(
+            Error::from_details(details(None)),
+            Error::HwiException(_)
+        ));
+        assert_eq!(
+            Error::Hwi("message".to_string(), Some(ErrorCode::BadArgument)).code(),
+            Some(ErrorCode::BadArgument)
+        );
+    }
+
+    #[test]
+    fn test_error_response_check() {
+        assert!(matches!(
+            HWIErrorResponse::check(
+                &json!({"error": "Sign message canceled by user", "code": -14})
+            ),
+            Err(Error::ActionCanceled(_))
+        ));
+        HWIErrorResponse::check(&json!(["not an error", -14])).unwrap();
+        HWIErrorResponse::check(&json!({"success": true})).unwrap();
+    }
+
+    #[test]
+    fn test_device_missing_field() {
+        let device: HWIDeviceInternal = serde_json::from_str(
+            r#"{"type": "trezor", "path": "udp:127.0.0.1:21324", "needs_pin_sent": false, "needs_passphrase_sent": false, "fingerprint": "95d8f670"}"#,
+        )
+        .unwrap();
+        match HWIDevice::try_from(device) {
+            Err(Error::InvalidValue(message)) => assert!(message.contains("model")),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod replay_tests {
+    use crate::error::{Error, ErrorCode};
+    use crate::implementations::mock_implementation::{MockDevice, MockHWI};
+    use crate::implementations::replay_implementation::{RecordingHWI, ReplayHWI};
+    use crate::types::{HWIAddressType, TESTNET};
+    use crate::HWIClient;
+    use std::str::FromStr;
+
+    use bitcoin::bip32::DerivationPath;
+
+    fn record_session(file: &std::path::Path) {
+        MockHWI::disconnect_all();
+        MockHWI::connect(MockDevice::from_seed(&[7; 32]).unwrap().with_error(
+            "signmessage",
+            ErrorCode::ActionCanceled,
+            "Sign message canceled by user",
+        ));
+
+        RecordingHWI::<MockHWI>::start_recording(file).unwrap();
+        let device = HWIClient::<RecordingHWI<MockHWI>>::enumerate()
+            .unwrap()
+            .remove(0)
+            .unwrap();
+        let client =
+            HWIClient::<RecordingHWI<MockHWI>>::get_client(&device, false, TESTNET).unwrap();
+        client
+            .get_keypool::<String>(true, false, HWIAddressType::Wit, false, None, None, 0, 5)
+            .unwrap();
+        let path = DerivationPath::from_str("m/44'/1'/0'/0/0").unwrap();
+        client.sign_message("Hello", &path).unwrap_err();
+        RecordingHWI::<MockHWI>::stop_recording();
+        MockHWI::disconnect_all();
+    }
+
+    #[test]
+    #[serial]
+    fn test_recording_write_error() {
+        let file = std::env::temp_dir().join("rust_hwi_test_recording_write_error.jsonl");
+        let device = MockHWI::connect_only(MockDevice::from_seed(&[7; 32]).unwrap());
+        RecordingHWI::<MockHWI>::start_recording(&file).unwrap();
+        let client =
+            HWIClient::<RecordingHWI<MockHWI>>::get_client(&device, false, TESTNET).unwrap();
+        assert!(RecordingHWI::<MockHWI>::take_recording_error().is_none());
+
+        // The recording can't be appended to anymore, the result of the device is still returned
+        std::fs::remove_file(&file).unwrap();
+        let path = DerivationPath::from_str("m/44'/1'/0'/0/0").unwrap();
+        client.sign_message("Hello", &path).unwrap();
+        RecordingHWI::<MockHWI>::stop_recording();
+        assert!(matches!(
+            RecordingHWI::<MockHWI>::take_recording_error(),
+            Some(Error::Io(_))
+        ));
+        assert!(RecordingHWI::<MockHWI>::take_recording_error().is_none());
+        MockHWI::disconnect_all();
+    }
+
+    #[test]
+    fn test_replay_install_hwilib() {
+        assert!(matches!(
+            HWIClient::<ReplayHWI>::install_hwilib(None),
+            Err(Error::UnsupportedAction(_))
+        ));
+    }
+
+    #[test]
+    #[serial]
+    fn test_replay_session() {
+        let file = std::env::temp_dir().join("rust_hwi_test_replay_session.jsonl");
+        record_session(&file);
+
+        ReplayHWI::load(&file).unwrap();
+        assert_eq!(ReplayHWI::remaining(), 4);
+        let device = HWIClient::<ReplayHWI>::enumerate()
+            .unwrap()
+            .remove(0)
+            .unwrap();
+        let client = HWIClient::<ReplayHWI>::get_client(&device, false, TESTNET).unwrap();
+        let keypool = client
+            .get_keypool::<String>(true, false, HWIAddressType::Wit, false, None, None, 0, 5)
+            .unwrap();
+        assert_eq!(keypool.len(), 1);
+        let path = DerivationPath::from_str("m/44'/1'/0'/0/0").unwrap();
+        match client.sign_message("Hello", &path) {
+            Err(Error::ActionCanceled(details)) => {
+                assert_eq!(details.code, Some(ErrorCode::ActionCanceled))
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(ReplayHWI::remaining(), 0);
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_recording_without_secrets() {
+        let file = std::env::temp_dir().join("rust_hwi_test_recording_secrets.jsonl");
+        MockHWI::disconnect_all();
+        MockHWI::connect(MockDevice::from_seed(&[7; 32]).unwrap());
+
+        RecordingHWI::<MockHWI>::start_recording(&file).unwrap();
+        let client = HWIClient::<RecordingHWI<MockHWI>>::find_device(
+            Some("secret-password"),
+            None,
+            None,
+            false,
+            bitcoin::Network::Testnet,
+        )
+        .unwrap();
+        client.send_pin("97531").unwrap_err();
+        client
+            .setup_device(Some("label"), Some("secret-setup"))
+            .unwrap();
+        client
+            .backup_device(Some("label"), Some("secret-backup"))
+            .unwrap();
+        RecordingHWI::<MockHWI>::stop_recording();
+        MockHWI::disconnect_all();
+
+        let recording = std::fs::read_to_string(&file).unwrap();
+        assert_eq!(recording.lines().count(), 4);
+        for secret in ["secret-password", "97531", "secret-setup", "secret-backup"] {
+            assert!(!recording.contains(secret), "{} was recorded", secret);
+        }
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_replay_argument_drift() {
+        let file = std::env::temp_dir().join("rust_hwi_test_replay_drift.jsonl");
+        record_session(&file);
+
+        ReplayHWI::load(&file).unwrap();
+        let device = HWIClient::<ReplayHWI>::enumerate()
+            .unwrap()
+            .remove(0)
+            .unwrap();
+        let client = HWIClient::<ReplayHWI>::get_client(&device, false, TESTNET).unwrap();
+        match client.get_keypool::<String>(true, true, HWIAddressType::Wit, false, None, None, 0, 5)
+        {
+            Err(Error::Hwi(message, None)) => assert!(message.contains("getkeypool")),
+            other => panic!("unexpected result {:?}", other),
+        }
+        std::fs::remove_file(file).unwrap();
+    }
+}
+
+#[cfg(all(test, feature = "async"))]
+mod async_tests {
+    use crate::implementations::mock_implementation::{MockDevice, MockHWI};
+    use crate::types::{HWIAddressType, TESTNET};
+    use crate::AsyncHWIClient;
+    use std::future::Future;
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc};
+    use std::task::{Context, Poll, Wake, Waker};
+    use std::thread::{self, Thread};
+
+    use bitcoin::bip32::DerivationPath;
+
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    fn connect_mock_device() -> AsyncHWIClient<MockHWI> {
+        let device = MockHWI::connect_only(MockDevice::from_seed(&[7; 32]).unwrap());
+        block_on(AsyncHWIClient::get_client(device, false, TESTNET)).unwrap()
+    }
+
+    #[test]
+    #[serial]
+    fn test_async_display_address_with_path() {
+        let client = connect_mock_device();
+        let path = DerivationPath::from_str("m/84'/1'/0'/0/0").unwrap();
+        let address = block_on(client.display_address_with_path(path, HWIAddressType::Wit));
+        assert!(address.is_ok());
+    }
+
+    #[test]
+    #[serial]
+    fn test_async_cancel_by_dropping() {
+        let client = connect_mock_device();
+        let runs = Arc::new(AtomicUsize::new(0));
+
+        // Keep the worker busy until the second call has been dropped
+        let (release, wait) = mpsc::channel::<()>();
+        let first = client.run(move |_| {
+            wait.recv().unwrap();
+            Ok(())
+        });
+        let counter = runs.clone();
+        let second = client.run(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        });
+        drop(second);
+        release.send(()).unwrap();
+        block_on(first).unwrap();
+
+        let counter = runs.clone();
+        block_on(client.run(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }))
+        .unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+    }
+}
+
+#[cfg(test)]
+mod shared_tests {
+    use crate::error::{Error, ErrorCode};
+    use crate::implementations::mock_implementation::{MockDevice, MockHWI};
+    use crate::types::{HWIDevice, TESTNET};
+    use crate::{LockPolicy, SharedHWIClient};
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use bitcoin::bip32::DerivationPath;
+
+    fn connect_mock_device() -> HWIDevice {
+        MockHWI::connect_only(MockDevice::from_seed(&[7; 32]).unwrap())
+    }
+
+    #[test]
+    #[serial]
+    fn test_shared_lock_policy() {
+        let device = connect_mock_device();
+        let first = SharedHWIClient::<MockHWI>::get_client(&device, false, TESTNET).unwrap();
+        let second = SharedHWIClient::<MockHWI>::get_client(&device, false, TESTNET)
+            .unwrap()
+            .lock_policy(LockPolicy::FailFast);
+
+        let guard = first.lock().unwrap();
+        match second.lock() {
+            Err(e @ Error::DeviceBusy(_)) => assert_eq!(e.code(), Some(ErrorCode::DeviceBusy)),
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+        assert!(matches!(
+            second.lock_with(LockPolicy::WaitFor(Duration::from_millis(20))),
+            Err(Error::DeviceBusy(_))
+        ));
+        drop(guard);
+
+        let path = DerivationPath::from_str("m/44'/1'/0'/0/0").unwrap();
+        second.lock().unwrap().get_xpub(&path, false).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_shared_serialises_calls() {
+        let device = connect_mock_device();
+        let shared =
+            Arc::new(SharedHWIClient::<MockHWI>::get_client(&device, false, TESTNET).unwrap());
+        let in_use = Arc::new(AtomicUsize::new(0));
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let shared = shared.clone();
+                let in_use = in_use.clone();
+                let calls = calls.clone();
+                thread::spawn(move || {
+                    let path = DerivationPath::from_str("m/44'/1'/0'/0/0").unwrap();
+                    for _ in 0..5 {
+                        shared
+                            .with_client(|client| {
+                                assert_eq!(in_use.fetch_add(1, Ordering::SeqCst), 0);
+                                let signature = client.sign_message("Hello", &path);
+                                thread::sleep(Duration::from_millis(1));
+                                in_use.fetch_sub(1, Ordering::SeqCst);
+                                signature
+                            })
+                            .unwrap();
+                        calls.fetch_add(1, Ordering::SeqCst);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 20);
+    }
+}
+
+#[cfg(test)]
+mod watcher_tests {
+    use crate::error::ErrorCode;
+    use crate::implementations::mock_implementation::{MockDevice, MockHWI};
+    use crate::types::{HWIDevice, HWIDeviceType, TESTNET};
+    use crate::watcher::DeviceTracker;
+    use crate::{DeviceEvent, DeviceWatcher, HWIClient, LockPolicy, SharedHWIClient};
+    use std::thread;
+    use std::time::Duration;
+
+    fn update(tracker: &mut DeviceTracker) -> Vec<DeviceEvent> {
+        tracker.update(HWIClient::<MockHWI>::enumerate_internal(None))
+    }
+
+    #[test]
+    #[serial]
+    fn test_device_tracker() {
+        MockHWI::disconnect_all();
+        let mut tracker = DeviceTracker::default();
+        assert!(update(&mut tracker).is_empty());
+
+        let device = MockDevice::from_seed(&[7; 32]).unwrap().with_pin("1234");
+        MockHWI::connect(device.clone());
+        let events = update(&mut tracker);
+        assert!(matches!(
+            events.as_slice(),
+            [DeviceEvent::Connected { device: None, .. }, DeviceEvent::Locked { path }]
+                if path == device.path()
+        ));
+        assert!(update(&mut tracker).is_empty());
+
+        let client = HWIClient::<MockHWI>::get_client(
+            &HWIDevice {
+                device_type: HWIDeviceType::Trezor,
+                model: "mock".to_string(),
+                path: device.path().to_string(),
+                needs_pin_sent: true,
+                needs_passphrase_sent: false,
+                fingerprint: Default::default(),
+            },
+            false,
+            TESTNET,
+        )
+        .unwrap();
+        client.prompt_pin().unwrap();
+        client.send_pin("1234").unwrap();
+        match update(&mut tracker).as_slice() {
+            [DeviceEvent::Unlocked {
+                device: Some(unlocked),
+                ..
+            }] => assert_eq!(unlocked.fingerprint, device.fingerprint()),
+            other => panic!("unexpected events {:?}", other),
+        }
+
+        let broken = MockDevice::from_seed(&[8; 32])
+            .unwrap()
+            .with_path("mock:broken")
+            .with_error("enumerate", ErrorCode::DeviceBusy, "Device is busy");
+        MockHWI::connect(broken);
+        let events = update(&mut tracker);
+        assert!(matches!(
+            events.as_slice(),
+            [DeviceEvent::Connected { device: None, .. }, DeviceEvent::Error { path: Some(path), error }]
+                if path == "mock:broken" && error.code() == Some(ErrorCode::DeviceBusy)
+        ));
+        assert!(update(&mut tracker).is_empty());
+
+        MockHWI::disconnect(device.path());
+        assert!(matches!(
+            update(&mut tracker).as_slice(),
+            [DeviceEvent::Disconnected { path }] if path == device.path()
+        ));
+    }
+
+    #[test]
+    #[serial]
+    fn test_device_watcher() {
+        MockHWI::disconnect_all();
+        let watcher = DeviceWatcher::start::<MockHWI>(Duration::from_millis(5));
+        let device = MockDevice::from_seed(&[7; 32]).unwrap();
+        MockHWI::connect(device.clone());
+        match watcher.events().recv_timeout(Duration::from_secs(5)) {
+            Ok(DeviceEvent::Connected {
+                device: Some(connected),
+                ..
+            }) => assert_eq!(connected.fingerprint, device.fingerprint()),
+            other => panic!("unexpected event {:?}", other),
+        }
+        MockHWI::disconnect_all();
+        assert!(matches!(
+            watcher.events().recv_timeout(Duration::from_secs(5)),
+            Ok(DeviceEvent::Disconnected { .. })
+        ));
+    }
+
+    #[test]
+    #[serial]
+    fn test_device_watcher_skips_busy_devices() {
+        let device = MockDevice::from_seed(&[7; 32]).unwrap();
+        let hwi_device = MockHWI::connect_only(device.clone());
+        let shared = SharedHWIClient::<MockHWI>::get_client(&hwi_device, false, TESTNET).unwrap();
+
+        let guard = shared.lock().unwrap();
+        let watcher = DeviceWatcher::start::<MockHWI>(Duration::from_millis(5));
+        assert!(watcher
+            .events()
+            .recv_timeout(Duration::from_millis(100))
+            .is_err());
+        drop(guard);
+        match watcher.events().recv_timeout(Duration::from_secs(5)) {
+            Ok(DeviceEvent::Connected {
+                device: Some(connected),
+                ..
+            }) => assert_eq!(connected.fingerprint, device.fingerprint()),
+            other => panic!("unexpected event {:?}", other),
+        }
+
+        // The watcher never locks the devices, failing fast never fails because of it
+        let shared = shared.lock_policy(LockPolicy::FailFast);
+        for _ in 0..20 {
+            drop(shared.lock().unwrap());
+            thread::sleep(Duration::from_millis(2));
+        }
+        MockHWI::disconnect_all();
+    }
+}
+
+#[cfg(test)]
+mod timeout_tests {
+    use crate::error::Error;
+    use crate::implementations::mock_implementation::{MockDevice, MockHWI};
+    use crate::types::{HWITimeouts, TESTNET};
+    use crate::HWIClient;
+    use std::str::FromStr;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use bitcoin::bip32::DerivationPath;
+
+    fn connect_slow_device(delay: Duration) -> HWIClient<MockHWI> {
+        let device = MockHWI::connect_only(
+            MockDevice::from_seed(&[7; 32])
+                .unwrap()
+                .with_delay("signmessage", delay),
+        );
+        HWIClient::<MockHWI>::get_client(&device, false, TESTNET).unwrap()
+    }
+
+    #[test]
+    #[serial]
+    fn test_confirmation_timeout() {
+        let client = connect_slow_device(Duration::from_millis(300)).with_timeouts(
+            HWITimeouts::new()
+                .query(Duration::from_secs(5))
+                .confirmation(Duration::from_millis(50)),
+        );
+        let path = DerivationPath::from_str("m/44'/1'/0'/0/0").unwrap();
+
+        match client.sign_message("hello", &path) {
+            Err(Error::Timeout(timeout)) => assert_eq!(timeout, Duration::from_millis(50)),
+            other => panic!("unexpected result {:?}", other),
+        }
+        // The device is still busy with the abandoned call, queries don't wait for it
+        let start = Instant::now();
+        assert!(matches!(
+            client.get_xpub(&path, false),
+            Err(Error::DeviceBusy(_))
+        ));
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        thread::sleep(Duration::from_millis(400));
+        client.get_xpub(&path, false).unwrap();
+        let client = client.with_timeouts(HWITimeouts::default());
+        client.sign_message("hello", &path).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_cancel() {
+        let client = connect_slow_device(Duration::from_millis(300));
+        let handle = client.cancel_handle();
+        let path = DerivationPath::from_str("m/44'/1'/0'/0/0").unwrap();
+
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            handle.cancel();
+        });
+        assert!(matches!(
+            client.sign_message("hello", &path),
+            Err(Error::Cancelled)
+        ));
+        canceller.join().unwrap();
+
+        // Cancelling only affects the calls in progress
+        thread::sleep(Duration::from_millis(400));
+        client.sign_message("hello", &path).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod coordinator_tests {
+    use crate::coordinator::{missing_fingerprints, required_fingerprints, signature_count};
+    use crate::error::{Error, ErrorCode};
+    use crate::implementations::mock_implementation::{MockDevice, MockHWI};
+    use crate::types::TESTNET;
+    use crate::PsbtCoordinator;
+    use std::collections::BTreeMap;
+    use std::str::FromStr;
+
+    use bitcoin::bip32::{DerivationPath, Fingerprint, KeySource, Xpriv, Xpub};
+    use bitcoin::blockdata::opcodes::all::{OP_CHECKMULTISIG, OP_PUSHNUM_2, OP_PUSHNUM_3};
+    use bitcoin::blockdata::script::Builder;
+    use bitcoin::locktime::absolute;
+    use bitcoin::psbt::Input;
+    use bitcoin::secp256k1::{self, Secp256k1};
+    use bitcoin::{ecdsa, taproot, transaction, Amount, NetworkKind, OutPoint, Psbt, ScriptBuf};
+    use bitcoin::{Transaction, TxIn, TxOut};
+
+    pub(super) const SEEDS: [[u8; 32]; 3] = [[1; 32], [2; 32], [3; 32]];
+
+    /// A PSBT spending from a 2-of-3 multisig of the devices with `SEEDS`.
+    pub(super) fn multisig_psbt() -> (Psbt, Vec<Fingerprint>) {
+        let secp = Secp256k1::new();
+        let path = DerivationPath::from_str("m/48'/1'/0'/2'/0/0").unwrap();
+        let mut bip32_derivation: BTreeMap<secp256k1::PublicKey, KeySource> = BTreeMap::new();
+        let mut fingerprints = Vec::new();
+        for seed in &SEEDS {
+            let xprv = Xpriv::new_master(NetworkKind::Test, seed).unwrap();
+            let public_key =
+                Xpub::from_priv(&secp, &xprv.derive_priv(&secp, &path).unwrap()).public_key;
+            bip32_derivation.insert(public_key, (xprv.fingerprint(&secp), path.clone()));
+            fingerprints.push(xprv.fingerprint(&secp));
+        }
+
+        let mut builder = Builder::new().push_opcode(OP_PUSHNUM_2);
+        for public_key in bip32_derivation.keys() {
+            builder = builder.push_slice(public_key.serialize());
+        }
+        let witness_script = builder
+            .push_opcode(OP_PUSHNUM_3)
+            .push_opcode(OP_CHECKMULTISIG)
+            .into_script();
+        let script_pubkey = ScriptBuf::new_p2wsh(&witness_script.wscript_hash());
+
+        let unsigned_tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(90_000),
+                script_pubkey: script_pubkey.clone(),
+            }],
+        };
+        let mut psbt = Psbt::from_unsigned_tx(unsigned_tx).unwrap();
+        psbt.inputs[0] = Input {
+            witness_utxo: Some(TxOut {
+                value: Amount::from_sat(100_000),
+                script_pubkey,
+            }),
+            witness_script: Some(witness_script),
+            bip32_derivation,
+            ..Default::default()
+        };
+        (psbt, fingerprints)
+    }
+
+    #[test]
+    fn test_required_fingerprints() {
+        let (psbt, fingerprints) = multisig_psbt();
+        let mut required = required_fingerprints(&psbt);
+        required.sort();
+        let mut expected = fingerprints.clone();
+        expected.sort();
+        assert_eq!(required, expected);
+        assert_eq!(missing_fingerprints(&psbt).len(), 3);
+    }
+
+    #[test]
+    fn test_signature_count() {
+        let secp = Secp256k1::new();
+        let xprv = Xpriv::new_master(NetworkKind::Test, &SEEDS[0]).unwrap();
+        let fingerprint = xprv.fingerprint(&secp);
+        let path = DerivationPath::from_str("m/86'/1'/0'/0/0").unwrap();
+        let public_key =
+            Xpub::from_priv(&secp, &xprv.derive_priv(&secp, &path).unwrap()).public_key;
+        let (x_only, _) = public_key.x_only_public_key();
+        let signature = secp256k1::ecdsa::Signature::from_compact(&[1; 64]).unwrap();
+
+        let (mut psbt, _) = multisig_psbt();
+        // Origins of internal keys without `tap_internal_key`, and an ECDSA signature by one,
+        // which only counts with the key in `bip32_derivation`
+        psbt.inputs[0] = Input {
+            tap_key_origins: [(x_only, (vec![], (fingerprint, path.clone())))].into(),
+            tap_key_sig: Some(taproot::Signature::from_slice(&[1; 64]).unwrap()),
+            partial_sigs: [(
+                bitcoin::PublicKey::new(public_key),
+                ecdsa::Signature::sighash_all(signature),
+            )]
+            .into(),
+            ..Default::default()
+        };
+        assert_eq!(signature_count(&psbt, fingerprint), 1);
+
+        psbt.inputs[0]
+            .bip32_derivation
+            .insert(public_key, (fingerprint, path));
+        assert_eq!(signature_count(&psbt, fingerprint), 2);
+        assert_eq!(signature_count(&psbt, Fingerprint::default()), 0);
+    }
+
+    #[test]
+    #[serial]
+    fn test_coordinator_sign() {
+        let (psbt, fingerprints) = multisig_psbt();
+        MockHWI::disconnect_all();
+        MockHWI::connect(MockDevice::from_seed(&SEEDS[0]).unwrap());
+        MockHWI::connect(MockDevice::from_seed(&SEEDS[1]).unwrap());
+
+        let report = PsbtCoordinator::<MockHWI>::new(TESTNET)
+            .sign(&psbt)
+            .unwrap();
+        assert_eq!(report.psbt.inputs[0].partial_sigs.len(), 2);
+        let mut signed = report.signed.clone();
+        signed.sort();
+        let mut expected = fingerprints[..2].to_vec();
+        expected.sort();
+        assert_eq!(signed, expected);
+        assert!(report.failed.is_empty());
+        // Enough for the 2-of-3 multisig, although the third key didn't sign
+        assert_eq!(report.missing, vec![fingerprints[2]]);
+        assert!(!report.all_keys_signed());
+    }
+
+    #[test]
+    #[serial]
+    fn test_coordinator_device_failure() {
+        let (psbt, fingerprints) = multisig_psbt();
+        MockHWI::disconnect_all();
+        MockHWI::connect(MockDevice::from_seed(&SEEDS[0]).unwrap());
+        MockHWI::connect(MockDevice::from_seed(&SEEDS[1]).unwrap().with_error(
+            "signtx",
+            ErrorCode::ActionCanceled,
+            "Sign transaction denied by user",
+        ));
+
+        let report = PsbtCoordinator::<MockHWI>::new(TESTNET)
+            .sign(&psbt)
+            .unwrap();
+        assert_eq!(report.signed, vec![fingerprints[0]]);
+        assert_eq!(report.failed.len(), 1);
+        let (fingerprint, error) = &report.failed[0];
+        assert_eq!(*fingerprint, fingerprints[1]);
+        assert!(matches!(error, Error::ActionCanceled(_)));
+        let mut missing = report.missing.clone();
+        missing.sort();
+        let mut expected = fingerprints[1..].to_vec();
+        expected.sort();
+        assert_eq!(missing, expected);
+    }
+}
+
+#[cfg(all(test, feature = "signer"))]
+mod signer_tests {
+    use crate::coordinator_tests::{multisig_psbt, SEEDS};
+    use crate::error::{Error, ErrorCode};
+    use crate::implementations::mock_implementation::{MockDevice, MockHWI};
+    use crate::signer::{check_unsigned_tx, create_wallet};
+    use crate::types::{HWIAddressType, HWIPassphrase, TESTNET};
+    use crate::{HWIClient, HWIClientBuilder, HWISigner};
+    use std::collections::BTreeMap;
+    use std::str::FromStr;
+
+    use bdk_wallet::bitcoin::bip32::{DerivationPath, KeySource, Xpriv, Xpub};
+    use bdk_wallet::bitcoin::locktime::absolute;
+    use bdk_wallet::bitcoin::psbt::{Input, PsbtSighashType};
+    use bdk_wallet::bitcoin::secp256k1::{self, Secp256k1};
+    use bdk_wallet::bitcoin::sighash::EcdsaSighashType;
+    use bdk_wallet::bitcoin::{transaction, Address, Amount, CompressedPublicKey, Network};
+    use bdk_wallet::bitcoin::{NetworkKind, OutPoint, Psbt, ScriptBuf, Sequence, Transaction};
+    use bdk_wallet::bitcoin::{TxIn, TxOut};
+    use bdk_wallet::signer::{SignerError, SignerId, TransactionSigner};
+    use bdk_wallet::{KeychainKind, SignOptions};
+
+    const SEED: [u8; 32] = [7; 32];
+
+    fn connect_signer() -> HWISigner<MockHWI> {
+        let device = MockHWI::connect_only(MockDevice::from_seed(&SEED).unwrap());
+        HWISigner::from_device(&device, TESTNET).unwrap()
+    }
+
+    fn derive(path: &str) -> (secp256k1::PublicKey, KeySource) {
+        let secp = Secp256k1::new();
+        let xprv = Xpriv::new_master(NetworkKind::Test, &SEED).unwrap();
+        let path = DerivationPath::from_str(path).unwrap();
+        let public_key =
+            Xpub::from_priv(&secp, &xprv.derive_priv(&secp, &path).unwrap()).public_key;
+        (public_key, (xprv.fingerprint(&secp), path))
+    }
+
+    fn psbt_spending(script_pubkey: ScriptBuf, input: Input) -> Psbt {
+        let unsigned_tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(90_000),
+                script_pubkey: script_pubkey.clone(),
+            }],
+        };
+        let mut psbt = Psbt::from_unsigned_tx(unsigned_tx).unwrap();
+        psbt.inputs[0] = Input {
+            witness_utxo: Some(TxOut {
+                value: Amount::from_sat(100_000),
+                script_pubkey,
+            }),
+            ..input
+        };
+        psbt
+    }
+
+    fn p2wpkh_psbt() -> Psbt {
+        let (public_key, source) = derive("m/84'/1'/0'/0/0");
+        let script_pubkey =
+            Address::p2wpkh(&CompressedPublicKey(public_key), Network::Testnet).script_pubkey();
+        let mut bip32_derivation = BTreeMap::new();
+        bip32_derivation.insert(public_key, source);
+        psbt_spending(
+            script_pubkey,
+            Input {
+                bip32_derivation,
+                ..Default::default()
+            },
+        )
+    }
+
+    fn p2tr_psbt() -> Psbt {
+        let secp = Secp256k1::new();
+        let (public_key, source) = derive("m/86'/1'/0'/0/0");
+        let internal_key = public_key.x_only_public_key().0;
+        let script_pubkey =
+            Address::p2tr(&secp, internal_key, None, Network::Testnet).script_pubkey();
+        let mut tap_key_origins = BTreeMap::new();
+        tap_key_origins.insert(internal_key, (vec![], source));
+        psbt_spending(
+            script_pubkey,
+            Input {
+                tap_internal_key: Some(internal_key),
+                tap_key_origins,
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_signer_witness_utxo() {
+        let signer = connect_signer();
+        let secp = Secp256k1::new();
+        let mut psbt = p2wpkh_psbt();
+
+        assert!(matches!(
+            signer.sign_transaction(&mut psbt, &SignOptions::default(), &secp),
+            Err(SignerError::MissingNonWitnessUtxo)
+        ));
+
+        let sign_options = SignOptions {
+            trust_witness_utxo: true,
+            ..Default::default()
+        };
+        signer
+            .sign_transaction(&mut psbt, &sign_options, &secp)
+            .unwrap();
+        assert!(psbt.inputs[0].final_script_witness.is_some());
+    }
+
+    #[test]
+    #[serial]
+    fn test_signer_try_finalize() {
+        let signer = connect_signer();
+        let secp = Secp256k1::new();
+        let mut psbt = p2wpkh_psbt();
+
+        let sign_options = SignOptions {
+            trust_witness_utxo: true,
+            try_finalize: false,
+            ..Default::default()
+        };
+        signer
+            .sign_transaction(&mut psbt, &sign_options, &secp)
+            .unwrap();
+        assert_eq!(psbt.inputs[0].partial_sigs.len(), 1);
+        assert!(psbt.inputs[0].final_script_witness.is_none());
+    }
+
+    #[test]
+    #[serial]
+    fn test_signer_sighash() {
+        let signer = connect_signer();
+        let secp = Secp256k1::new();
+        let mut psbt = p2wpkh_psbt();
+        psbt.inputs[0].sighash_type = Some(PsbtSighashType::from(EcdsaSighashType::None));
+
+        let mut sign_options = SignOptions {
+            trust_witness_utxo: true,
+            try_finalize: false,
+            ..Default::default()
+        };
+        assert!(matches!(
+            signer.sign_transaction(&mut psbt, &sign_options, &secp),
+            Err(SignerError::NonStandardSighash)
+        ));
+
+        sign_options.allow_all_sighashes = true;
+        signer
+            .sign_transaction(&mut psbt, &sign_options, &secp)
+            .unwrap();
+        assert_eq!(psbt.inputs[0].partial_sigs.len(), 1);
+    }
+
+    #[test]
+    #[serial]
+    fn test_signer_default_sighash_ecdsa() {
+        let signer = connect_signer();
+        let secp = Secp256k1::new();
+        let mut psbt = p2wpkh_psbt();
+        // SIGHASH_DEFAULT is only valid for taproot inputs
+        psbt.inputs[0].sighash_type = Some(PsbtSighashType::from_u32(0));
+
+        let sign_options = SignOptions {
+            trust_witness_utxo: true,
+            try_finalize: false,
+            ..Default::default()
+        };
+        assert!(matches!(
+            signer.sign_transaction(&mut psbt, &sign_options, &secp),
+            Err(SignerError::NonStandardSighash)
+        ));
+        assert!(psbt.inputs[0].partial_sigs.is_empty());
+    }
+
+    #[test]
+    #[serial]
+    fn test_signer_tap_internal_key() {
+        let signer = connect_signer();
+        let secp = Secp256k1::new();
+        let mut psbt = p2tr_psbt();
+
+        let mut sign_options = SignOptions {
+            sign_with_tap_internal_key: false,
+            try_finalize: false,
+            ..Default::default()
+        };
+        signer
+            .sign_transaction(&mut psbt, &sign_options, &secp)
+            .unwrap();
+        assert!(psbt.inputs[0].tap_key_sig.is_none());
+
+        sign_options.sign_with_tap_internal_key = true;
+        signer
+            .sign_transaction(&mut psbt, &sign_options, &secp)
+            .unwrap();
+        assert!(psbt.inputs[0].tap_key_sig.is_some());
+    }
+
+    #[test]
+    #[serial]
+    fn test_signer_no_signature() {
+        let signer = connect_signer();
+        let secp = Secp256k1::new();
+        // The key doesn't derive from its origin, the device signs with another one
+        let mut psbt = p2wpkh_psbt();
+        let (_, source) = derive("m/84'/1'/0'/0/1");
+        let key = *psbt.inputs[0].bip32_derivation.keys().next().unwrap();
+        psbt.inputs[0].bip32_derivation.insert(key, source);
+        let original = psbt.clone();
+
+        let sign_options = SignOptions {
+            trust_witness_utxo: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            signer.sign_transaction(&mut psbt, &sign_options, &secp),
+            Err(SignerError::External(_))
+        ));
+        assert_eq!(psbt, original);
+    }
+
+    #[test]
+    fn test_check_unsigned_tx() {
+        let original = p2wpkh_psbt().unsigned_tx;
+        assert!(check_unsigned_tx(&original, &original.clone()).is_ok());
+
+        let mut signed = original.clone();
+        signed.output[0].value = Amount::from_sat(1_000);
+        match check_unsigned_tx(&original, &signed) {
+            Err(SignerError::External(e)) => assert!(e.contains("outputs")),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let mut signed = original.clone();
+        signed.input[0].sequence = Sequence::ZERO;
+        match check_unsigned_tx(&original, &signed) {
+            Err(SignerError::External(e)) => assert!(e.contains("sequence numbers")),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let mut signed = original.clone();
+        signed.lock_time = absolute::LockTime::from_consensus(800_000);
+        match check_unsigned_tx(&original, &signed) {
+            Err(SignerError::External(e)) => assert!(e.contains("locktime")),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_signer_fingerprint_mismatch() {
+        let device = MockDevice::from_seed(&SEED).unwrap();
+        let mut hwi_device = MockHWI::connect_only(device.clone());
+        let other = MockDevice::from_seed(&[8; 32]).unwrap().fingerprint();
+        hwi_device.fingerprint = other;
+        let signer = HWISigner::<MockHWI>::from_device(&hwi_device, TESTNET)
+            .unwrap()
+            .with_reconnect(true);
+
+        match signer.check_fingerprint() {
+            Err(Error::FingerprintMismatch(expected, actual)) => {
+                assert_eq!(expected, other);
+                assert_eq!(actual, device.fingerprint());
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        let sign_options = SignOptions {
+            trust_witness_utxo: true,
+            ..Default::default()
+        };
+        // The PSBT expects a signature from the missing device
+        let mut psbt = p2wpkh_psbt();
+        for (fingerprint, _) in psbt.inputs[0].bip32_derivation.values_mut() {
+            *fingerprint = other;
+        }
+        assert!(matches!(
+            signer.sign_transaction(&mut psbt, &sign_options, &Secp256k1::new()),
+            Err(SignerError::External(_))
+        ));
+    }
+
+    #[test]
+    #[serial]
+    fn test_signer_reconnect() {
+        let reconnecting = connect_signer().with_reconnect(true);
+        let device = HWIClient::<MockHWI>::enumerate()
+            .unwrap()
+            .remove(0)
+            .unwrap();
+        let signer = HWISigner::<MockHWI>::from_device(&device, TESTNET).unwrap();
+        // The device is plugged in another port
+        MockHWI::disconnect_all();
+        MockHWI::connect(
+            MockDevice::from_seed(&SEED)
+                .unwrap()
+                .with_path("mock:other"),
+        );
+
+        match signer.check_fingerprint() {
+            Err(e) => assert_eq!(e.code(), Some(ErrorCode::DeviceConnError)),
+            other => panic!("unexpected result {:?}", other),
+        }
+        reconnecting.check_fingerprint().unwrap();
+        let sign_options = SignOptions {
+            trust_witness_utxo: true,
+            try_finalize: false,
+            ..Default::default()
+        };
+        let mut psbt = p2wpkh_psbt();
+        reconnecting
+            .sign_transaction(&mut psbt, &sign_options, &Secp256k1::new())
+            .unwrap();
+        assert_eq!(psbt.inputs[0].partial_sigs.len(), 1);
+    }
+
+    #[test]
+    #[serial]
+    fn test_signer_reconnect_with_passphrase() {
+        MockHWI::disconnect_all();
+        MockHWI::connect(
+            MockDevice::from_seed(&SEED)
+                .unwrap()
+                .with_needs_passphrase_sent(true),
+        );
+        let passphrase = HWIPassphrase::Host("passphrase".to_string());
+        let open = || {
+            HWIClientBuilder::<MockHWI>::new()
+                .chain(TESTNET)
+                .passphrase(passphrase.clone())
+                .build()
+                .unwrap()
+        };
+        let forgetful = HWISigner::from_client(open(), TESTNET)
+            .unwrap()
+            .with_reconnect(true);
+        let signer = HWISigner::from_client(open(), TESTNET)
+            .unwrap()
+            .with_reconnect(true)
+            .with_passphrase(passphrase.clone());
+        // The device is plugged in another port
+        MockHWI::disconnect_all();
+        MockHWI::connect(
+            MockDevice::from_seed(&SEED)
+                .unwrap()
+                .with_needs_passphrase_sent(true)
+                .with_path("mock:other"),
+        );
+
+        // Without the passphrase, the device is enumerated without its fingerprint
+        match forgetful.check_fingerprint() {
+            Err(Error::DeviceNotReady(details)) => assert!(details.message.contains("Passphrase")),
+            other => panic!("unexpected result {:?}", other),
+        }
+        signer.check_fingerprint().unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_create_wallet() {
+        let device = MockDevice::from_seed(&SEED).unwrap();
+        let hwi_device = MockHWI::connect_only(device.clone());
+        let client = HWIClient::<MockHWI>::get_client(&hwi_device, false, TESTNET).unwrap();
+
+        let (mut wallet, signer) =
+            create_wallet(client, Network::Testnet, 1, HWIAddressType::Wit).unwrap();
+        let external = wallet.public_descriptor(KeychainKind::External).to_string();
+        let internal = wallet.public_descriptor(KeychainKind::Internal).to_string();
+        assert!(external.starts_with(&format!("wpkh([{}/84'/1'/1']", device.fingerprint())));
+        assert!(external.contains("/0/*)"));
+        assert!(internal.starts_with("wpkh("));
+        assert!(internal.contains("/1/*)"));
+
+        let (public_key, _) = derive("m/84'/1'/1'/0/0");
+        assert_eq!(
+            wallet.reveal_next_address(KeychainKind::External).address,
+            Address::p2wpkh(&CompressedPublicKey(public_key), Network::Testnet)
+        );
+
+        let secp = Secp256k1::new();
+        for keychain in [KeychainKind::External, KeychainKind::Internal] {
+            let signers = wallet.get_signers(keychain);
+            let ids: Vec<_> = signers.signers().iter().map(|s| s.id(&secp)).collect();
+            assert_eq!(ids, vec![SignerId::Fingerprint(device.fingerprint())]);
+        }
+
+        // Both keychains call the signer, the second time with nothing left to sign
+        let address = wallet.reveal_next_address(KeychainKind::External).address;
+        let mut psbt = psbt_spending(address.script_pubkey(), Input::default());
+        let sign_options = SignOptions {
+            trust_witness_utxo: true,
+            ..Default::default()
+        };
+        assert!(wallet.sign(&mut psbt, sign_options).unwrap());
+        assert_eq!(signer.signatures_added(), 1);
+    }
+
+    #[test]
+    #[serial]
+    fn test_create_wallet_network_mismatch() {
+        let hwi_device = MockHWI::connect_only(MockDevice::from_seed(&SEED).unwrap());
+        let client = HWIClient::<MockHWI>::get_client(&hwi_device, false, TESTNET).unwrap();
+        assert_eq!(client.chain().network(), Network::Testnet);
+
+        match create_wallet(client, Network::Bitcoin, 0, HWIAddressType::Wit) {
+            Err(Error::InvalidValue(message)) => assert!(message.contains("bitcoin")),
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_signer_multisig() {
+        MockHWI::disconnect_all();
+        let bystander = MockDevice::from_seed(&[9; 32]).unwrap().with_error(
+            "signtx",
+            ErrorCode::UnknownError,
+            "nothing to sign",
+        );
+        MockHWI::connect(bystander.clone());
+        for seed in &SEEDS[..2] {
+            MockHWI::connect(MockDevice::from_seed(seed).unwrap());
+        }
+        let signer = |fingerprint| {
+            let device = HWIClient::<MockHWI>::enumerate()
+                .unwrap()
+                .into_iter()
+                .map(Result::unwrap)
+                .find(|device| device.fingerprint == fingerprint)
+                .unwrap();
+            HWISigner::<MockHWI>::from_device(&device, TESTNET).unwrap()
+        };
+
+        // A second input paying to a single key of the first device
+        let (mut psbt, fingerprints) = multisig_psbt();
+        let secp = Secp256k1::new();
+        let path = DerivationPath::from_str("m/84'/1'/0'/0/0").unwrap();
+        let xprv = Xpriv::new_master(NetworkKind::Test, &SEEDS[0]).unwrap();
+        let public_key =
+            Xpub::from_priv(&secp, &xprv.derive_priv(&secp, &path).unwrap()).public_key;
+        let mut bip32_derivation = BTreeMap::new();
+        bip32_derivation.insert(public_key, (fingerprints[0], path));
+        psbt.unsigned_tx.input.push(TxIn {
+            previous_output: OutPoint {
+                vout: 1,
+                ..OutPoint::null()
+            },
+            ..Default::default()
+        });
+        psbt.inputs.push(Input {
+            witness_utxo: Some(TxOut {
+                value: Amount::from_sat(100_000),
+                script_pubkey: Address::p2wpkh(&CompressedPublicKey(public_key), Network::Testnet)
+                    .script_pubkey(),
+            }),
+            bip32_derivation,
+            ..Default::default()
+        });
+
+        let sign_options = SignOptions {
+            trust_witness_utxo: true,
+            ..Default::default()
+        };
+        let bystander = signer(bystander.fingerprint());
+        assert_eq!(
+            bystander
+                .sign_psbt(&mut psbt, &sign_options, &secp)
+                .unwrap(),
+            0
+        );
+
+        let second = signer(fingerprints[1]);
+        assert_eq!(
+            second.sign_psbt(&mut psbt, &sign_options, &secp).unwrap(),
+            1
+        );
+        assert_eq!(second.signatures_added(), 1);
+        assert_eq!(psbt.inputs[0].partial_sigs.len(), 1);
+        assert!(psbt.inputs[1].partial_sigs.is_empty());
+
+        let first = signer(fingerprints[0]);
+        first
+            .sign_transaction(&mut psbt, &sign_options, &secp)
+            .unwrap();
+        assert_eq!(first.signatures_added(), 2);
+        // Both inputs have enough signatures
+        assert!(psbt.inputs[0].final_script_witness.is_some());
+        assert!(psbt.inputs[1].final_script_witness.is_some());
+
+        // Nothing is left to sign
+        assert_eq!(
+            second.sign_psbt(&mut psbt, &sign_options, &secp).unwrap(),
+            0
+        );
+    }
+}
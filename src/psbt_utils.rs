@@ -0,0 +1,81 @@
+//! Reading the signatures of a PSBT, shared by the [`coordinator`](crate::coordinator) and the
+//! signer.
+//!
+//! Keys are attributed to a device through their origin in `bip32_derivation`, or in
+//! `tap_key_origins` for taproot. A `tap_key_origins` entry without leaf hashes is the internal
+//! key, signing for the key path.
+
+use bitcoin::bip32::{Fingerprint, KeySource};
+use bitcoin::psbt::Input;
+use bitcoin::Psbt;
+
+/// Returns the number of signatures in `psbt` made by the keys of the device with `fingerprint`,
+/// as found in the key origins. The signatures of finalized inputs aren't known anymore.
+pub fn signature_count(psbt: &Psbt, fingerprint: Fingerprint) -> usize {
+    let mut count = 0;
+    for input in &psbt.inputs {
+        let has_fingerprint = |origin: &KeySource| origin.0 == fingerprint;
+        count += input
+            .partial_sigs
+            .keys()
+            .filter(|key| {
+                input
+                    .bip32_derivation
+                    .get(&key.inner)
+                    .map_or(false, has_fingerprint)
+            })
+            .count();
+        count += input
+            .tap_script_sigs
+            .keys()
+            .filter(|(key, _)| {
+                input
+                    .tap_key_origins
+                    .get(key)
+                    .map_or(false, |(_, origin)| has_fingerprint(origin))
+            })
+            .count();
+        let key_path_signed = input.tap_key_sig.is_some()
+            && input
+                .tap_key_origins
+                .values()
+                .any(|(leaf_hashes, origin)| leaf_hashes.is_empty() && has_fingerprint(origin));
+        if key_path_signed {
+            count += 1;
+        }
+    }
+    count
+}
+
+pub(crate) fn is_finalized(input: &Input) -> bool {
+    input.final_script_sig.is_some() || input.final_script_witness.is_some()
+}
+
+/// Returns the fingerprints of the keys that didn't sign `input` yet.
+pub(crate) fn unsigned_fingerprints(input: &Input) -> Vec<Fingerprint> {
+    let ecdsa = input
+        .bip32_derivation
+        .iter()
+        .filter(|(key, _)| {
+            !input
+                .partial_sigs
+                .keys()
+                .any(|signed| signed.inner == **key)
+        })
+        .map(|(_, (fingerprint, _))| *fingerprint);
+    let schnorr = input
+        .tap_key_origins
+        .iter()
+        .filter(|(key, (leaf_hashes, _))| {
+            if leaf_hashes.is_empty() {
+                // The internal key, signing for the key path
+                input.tap_key_sig.is_none()
+            } else {
+                !leaf_hashes
+                    .iter()
+                    .any(|leaf_hash| input.tap_script_sigs.contains_key(&(**key, *leaf_hash)))
+            }
+        })
+        .map(|(_, (_, (fingerprint, _)))| *fingerprint);
+    ecdsa.chain(schnorr).collect()
+}
@@ -0,0 +1,198 @@
+//! [`HWIClient`] shared between threads.
+//!
+//! Hardware wallets handle a single request at a time. [`SharedHWIClient`] can be wrapped in an
+//! [`Arc`] and handed to several tasks, and serialises their calls: a caller must first
+//! [`lock`](SharedHWIClient::lock) the device, and other callers either wait for it to be
+//! released or fail with [`Error::DeviceBusy`], according to the [`LockPolicy`].
+//!
+//! Locks are per device path and process-wide, so two clients opened for the same device also
+//! exclude each other.
+
+use std::ops::Deref;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError, Weak};
+use std::time::{Duration, Instant};
+
+use crate::error::{Error, ErrorCode, HWIErrorDetails};
+use crate::interface::HWIClient;
+use crate::types::{HWIChain, HWIDevice, HWIImplementation, HWIPassphrase};
+
+/// Locks of the devices currently used by a [`SharedHWIClient`], by device path.
+static DEVICE_LOCKS: Mutex<Vec<(String, Weak<DeviceLock>)>> = Mutex::new(Vec::new());
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Returns whether a [`SharedHWIClient`] is using one of the devices.
+pub(crate) fn any_device_busy() -> bool {
+    lock(&DEVICE_LOCKS)
+        .iter()
+        .filter_map(|(_, device_lock)| device_lock.upgrade())
+        .any(|device_lock| *lock(&device_lock.busy))
+}
+
+/// What to do when locking a device already in use.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
+pub enum LockPolicy {
+    /// Fail immediately with [`Error::DeviceBusy`].
+    FailFast,
+    /// Wait until the device is released.
+    #[default]
+    Wait,
+    /// Wait until the device is released, failing with [`Error::DeviceBusy`] after the duration.
+    WaitFor(Duration),
+}
+
+#[derive(Debug, Default)]
+struct DeviceLock {
+    busy: Mutex<bool>,
+    released: Condvar,
+}
+
+impl DeviceLock {
+    /// Returns the lock of the device at `path`, shared by every client of the device.
+    fn for_path(path: &str) -> Arc<DeviceLock> {
+        let mut locks = lock(&DEVICE_LOCKS);
+        locks.retain(|(_, device_lock)| device_lock.strong_count() > 0);
+        let existing = locks
+            .iter()
+            .filter(|(device_path, _)| device_path == path)
+            .find_map(|(_, device_lock)| device_lock.upgrade());
+        existing.unwrap_or_else(|| {
+            let device_lock = Arc::new(DeviceLock::default());
+            locks.push((path.to_string(), Arc::downgrade(&device_lock)));
+            device_lock
+        })
+    }
+
+    fn acquire(&self, path: &str, policy: LockPolicy) -> Result<(), Error> {
+        let busy_error = || {
+            Error::DeviceBusy(HWIErrorDetails {
+                message: format!("device {} is in use", path),
+                code: Some(ErrorCode::DeviceBusy),
+                traceback: None,
+            })
+        };
+
+        let mut busy = lock(&self.busy);
+        match policy {
+            LockPolicy::FailFast if *busy => return Err(busy_error()),
+            LockPolicy::FailFast => {}
+            LockPolicy::Wait => {
+                while *busy {
+                    busy = self
+                        .released
+                        .wait(busy)
+                        .unwrap_or_else(PoisonError::into_inner);
+                }
+            }
+            LockPolicy::WaitFor(timeout) => {
+                let deadline = Instant::now() + timeout;
+                while *busy {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(busy_error());
+                    }
+                    busy = match self.released.wait_timeout(busy, deadline - now) {
+                        Ok((busy, _)) => busy,
+                        Err(e) => e.into_inner().0,
+                    };
+                }
+            }
+        }
+        *busy = true;
+        Ok(())
+    }
+
+    fn release(&self) {
+        *lock(&self.busy) = false;
+        self.released.notify_one();
+    }
+}
+
+/// [`HWIClient`] that can be shared between threads, giving exclusive access to the device to one
+/// caller at a time.
+///
+/// When several callers are waiting, the device is handed to them in no particular order.
+#[derive(Debug)]
+pub struct SharedHWIClient<T: HWIImplementation> {
+    client: HWIClient<T>,
+    path: String,
+    device_lock: Arc<DeviceLock>,
+    policy: LockPolicy,
+}
+
+impl<T: HWIImplementation> SharedHWIClient<T> {
+    /// Shares `client`, which must have been opened for `device`.
+    pub fn new(client: HWIClient<T>, device: &HWIDevice) -> Self {
+        SharedHWIClient {
+            client,
+            path: device.path.clone(),
+            device_lock: DeviceLock::for_path(&device.path),
+            policy: LockPolicy::default(),
+        }
+    }
+
+    /// Opens `device` and shares the client, see [`HWIClient::get_client`].
+    pub fn get_client(device: &HWIDevice, expert: bool, chain: HWIChain) -> Result<Self, Error> {
+        let client = HWIClient::get_client(device, expert, chain)?;
+        Ok(Self::new(client, device))
+    }
+
+    /// Opens `device` with a passphrase and shares the client, see
+    /// [`HWIClient::get_client_with_passphrase`].
+    pub fn get_client_with_passphrase(
+        device: &HWIDevice,
+        expert: bool,
+        chain: HWIChain,
+        passphrase: &HWIPassphrase,
+    ) -> Result<Self, Error> {
+        let client = HWIClient::get_client_with_passphrase(device, expert, chain, passphrase)?;
+        Ok(Self::new(client, device))
+    }
+
+    /// Sets the policy used by [`lock`](Self::lock). Defaults to [`LockPolicy::Wait`].
+    pub fn lock_policy(mut self, policy: LockPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Locks the device, returning a guard giving access to the client until it's dropped.
+    pub fn lock(&self) -> Result<SharedHWIClientGuard<'_, T>, Error> {
+        self.lock_with(self.policy)
+    }
+
+    /// Like [`lock`](Self::lock), using `policy` instead of the one of the client.
+    pub fn lock_with(&self, policy: LockPolicy) -> Result<SharedHWIClientGuard<'_, T>, Error> {
+        self.device_lock.acquire(&self.path, policy)?;
+        Ok(SharedHWIClientGuard { shared: self })
+    }
+
+    /// Locks the device for the duration of `f`.
+    pub fn with_client<R>(
+        &self,
+        f: impl FnOnce(&HWIClient<T>) -> Result<R, Error>,
+    ) -> Result<R, Error> {
+        f(&*self.lock()?)
+    }
+}
+
+/// Exclusive access to the device of a [`SharedHWIClient`], released when dropped.
+#[derive(Debug)]
+pub struct SharedHWIClientGuard<'a, T: HWIImplementation> {
+    shared: &'a SharedHWIClient<T>,
+}
+
+impl<T: HWIImplementation> Deref for SharedHWIClientGuard<'_, T> {
+    type Target = HWIClient<T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.shared.client
+    }
+}
+
+impl<T: HWIImplementation> Drop for SharedHWIClientGuard<'_, T> {
+    fn drop(&mut self) {
+        self.shared.device_lock.release();
+    }
+}
@@ -1,55 +1,430 @@
+use bdk_wallet::bitcoin::absolute::LockTime;
 use bdk_wallet::bitcoin::bip32::Fingerprint;
+use bdk_wallet::bitcoin::psbt::Input;
 use bdk_wallet::bitcoin::secp256k1::{All, Secp256k1};
-use bdk_wallet::bitcoin::Psbt;
+use bdk_wallet::bitcoin::sighash::{EcdsaSighashType, TapSighashType};
+use bdk_wallet::bitcoin::taproot::TapLeafHash;
+use bdk_wallet::bitcoin::{Network, Psbt, Transaction};
+use bdk_wallet::miniscript::psbt::PsbtExt;
+use bdk_wallet::signer::{SignerOrdering, TapLeavesOptions};
+use bdk_wallet::{KeychainKind, SignOptions, Wallet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
 
 use crate::error::Error;
-use crate::types::{HWIChain, HWIDevice};
-use crate::HWIClient;
+#[cfg(feature = "python")]
+use crate::implementations::python_implementation::PythonHWI;
+use crate::psbt_utils::{is_finalized, signature_count, unsigned_fingerprints};
+use crate::types::{HWIAddressType, HWIChain, HWIDevice, HWIImplementation, HWIPassphrase};
+use crate::{HWIClient, HWIClientBuilder};
 
 use bdk_wallet::signer::{SignerCommon, SignerError, SignerId, TransactionSigner};
 
 #[derive(Debug)]
 /// Custom signer for Hardware Wallets
 ///
-/// This ignores `sign_options` and leaves the decisions up to the hardware wallet.
-pub struct HWISigner {
+/// The [`SignOptions`] are enforced around the device: the PSBT is checked before being sent,
+/// the taproot keys the options exclude are hidden from the device, the signatures it returns
+/// for them anyway are dropped, and the PSBT is finalized afterwards if asked to.
+///
+/// The PSBT returned by the device is only kept if it spends and pays the same as the one sent,
+/// and the device signed with its keys when it had any to sign with.
+///
+/// Before signing, the signer checks that the device still has the fingerprint it was created
+/// for, as the user may have swapped devices or changed the passphrase.
+///
+/// Only the inputs with keys of the device are signed, the others are left to the other signers:
+/// one `HWISigner` per device can be added to the wallet of a multisig descriptor.
+pub struct HWISigner<T: HWIImplementation> {
     fingerprint: Fingerprint,
-    client: HWIClient,
+    chain: HWIChain,
+    expert: bool,
+    passphrase: HWIPassphrase,
+    reconnect: bool,
+    client: Mutex<HWIClient<T>>,
+    signatures: AtomicUsize,
 }
 
-impl HWISigner {
+/// [`HWISigner`] reaching the device through a [`PythonHWIClient`](crate::PythonHWIClient).
+#[cfg(feature = "python")]
+pub type PythonHWISigner = HWISigner<PythonHWI>;
+
+impl<T: HWIImplementation> HWISigner<T> {
     /// Create an instance from the specified device and chain
-    pub fn from_device(device: &HWIDevice, chain: HWIChain) -> Result<HWISigner, Error> {
-        let client = HWIClient::get_client(device, false, chain)?;
+    pub fn from_device(device: &HWIDevice, chain: HWIChain) -> Result<HWISigner<T>, Error> {
+        let client = HWIClient::get_client(device, false, chain.clone())?;
         Ok(HWISigner {
             fingerprint: device.fingerprint,
-            client,
+            chain,
+            expert: false,
+            passphrase: HWIPassphrase::Empty,
+            reconnect: false,
+            client: Mutex::new(client),
+            signatures: AtomicUsize::new(0),
+        })
+    }
+
+    /// Create an instance signing with an already opened client, for the device with the given
+    /// chain
+    pub fn from_client(client: HWIClient<T>, chain: HWIChain) -> Result<HWISigner<T>, Error> {
+        Ok(HWISigner {
+            fingerprint: client.get_master_fingerprint()?,
+            chain,
+            expert: false,
+            passphrase: HWIPassphrase::Empty,
+            reconnect: false,
+            client: Mutex::new(client),
+            signatures: AtomicUsize::new(0),
         })
     }
+
+    /// Sets whether to look for the device by fingerprint when it can't be reached, or has
+    /// another fingerprint, at the path it was opened at. Defaults to `false`.
+    ///
+    /// The device is reopened with the [passphrase](Self::with_passphrase), the
+    /// [expert](Self::with_expert) flag and the timeouts of the current client.
+    pub fn with_reconnect(mut self, reconnect: bool) -> Self {
+        self.reconnect = reconnect;
+        self
+    }
+
+    /// Sets the passphrase to find and reopen the device with when reconnecting, which should be
+    /// the one the client was opened with. Defaults to [`HWIPassphrase::Empty`].
+    pub fn with_passphrase(mut self, passphrase: HWIPassphrase) -> Self {
+        self.passphrase = passphrase;
+        self
+    }
+
+    /// Sets whether to reopen the device in expert mode when reconnecting. Defaults to `false`.
+    pub fn with_expert(mut self, expert: bool) -> Self {
+        self.expert = expert;
+        self
+    }
+
+    /// Returns the number of signatures the device added the last time it was asked to sign.
+    ///
+    /// Calls with nothing for the device to sign leave it unchanged, so that it still tells what
+    /// the signer contributed once [`Wallet::sign`] called it for both keychains.
+    pub fn signatures_added(&self) -> usize {
+        self.signatures.load(Ordering::SeqCst)
+    }
+
+    /// Signs the inputs of `psbt` with keys of the device, like
+    /// [`sign_transaction`](TransactionSigner::sign_transaction), returning the number of
+    /// signatures the device added.
+    ///
+    /// The device isn't called when it has nothing to sign. An input finalized by the device
+    /// counts as a single signature.
+    pub fn sign_psbt(
+        &self,
+        psbt: &mut Psbt,
+        sign_options: &SignOptions,
+        secp: &Secp256k1<All>,
+    ) -> Result<usize, SignerError> {
+        let mut request = psbt.clone();
+        filter_tap_keys(&mut request, sign_options);
+        let ours: Vec<bool> = request
+            .inputs
+            .iter()
+            .map(|input| {
+                !is_finalized(input) && unsigned_fingerprints(input).contains(&self.fingerprint)
+            })
+            .collect();
+        if !ours.contains(&true) {
+            return Ok(0);
+        }
+        self.signatures.store(0, Ordering::SeqCst);
+        check_psbt(psbt, &ours, sign_options)?;
+
+        let signatures = signature_count(psbt, self.fingerprint);
+        let mut signed = self
+            .client()
+            .and_then(|client| client.sign_tx(&request))
+            .map_err(|e| {
+                SignerError::External(format!("While signing with hardware wallet: {}", e))
+            })?
+            .psbt;
+        check_unsigned_tx(&psbt.unsigned_tx, &signed.unsigned_tx)?;
+        filter_tap_signatures(&mut signed, psbt, sign_options);
+        // Ignore what the device did to the inputs of the other signers
+        for ((signed, input), ours) in signed.inputs.iter_mut().zip(&psbt.inputs).zip(&ours) {
+            if !ours {
+                *signed = input.clone();
+            }
+        }
+        // Combine a copy, leaving the PSBT untouched on failure
+        let mut combined = psbt.clone();
+        combined.combine(signed).map_err(|e| {
+            SignerError::External(format!(
+                "While combining the PSBT signed by the hardware wallet: {}",
+                e
+            ))
+        })?;
+        // A device finalizing inputs itself drops the signatures
+        let finalized = combined
+            .inputs
+            .iter()
+            .zip(&psbt.inputs)
+            .filter(|(combined, input)| is_finalized(combined) && !is_finalized(input))
+            .count();
+        let added = signature_count(&combined, self.fingerprint).saturating_sub(signatures);
+        if added + finalized == 0 {
+            return Err(SignerError::External(
+                "The hardware wallet didn't add any signature".to_string(),
+            ));
+        }
+        *psbt = combined;
+
+        if can_finalize(psbt, sign_options) {
+            for index in 0..psbt.inputs.len() {
+                if !is_finalized(&psbt.inputs[index]) {
+                    // Other signers may still have to sign the input
+                    let _ = psbt.finalize_inp_mut(secp, index);
+                }
+            }
+        }
+        self.signatures.store(added + finalized, Ordering::SeqCst);
+        Ok(added + finalized)
+    }
+
+    /// Checks that the device has the fingerprint of the signer, failing with
+    /// [`Error::FingerprintMismatch`] otherwise.
+    ///
+    /// If reconnecting is enabled, the device is looked for among all the connected devices
+    /// instead of failing.
+    pub fn check_fingerprint(&self) -> Result<(), Error> {
+        self.client().map(|_| ())
+    }
+
+    /// Returns the client, after checking the device has the fingerprint of the signer.
+    fn client(&self) -> Result<MutexGuard<'_, HWIClient<T>>, Error> {
+        let mut client = self.client.lock().unwrap_or_else(PoisonError::into_inner);
+        let error = match client.get_master_fingerprint() {
+            Ok(fingerprint) if fingerprint == self.fingerprint => return Ok(client),
+            Ok(fingerprint) => Error::FingerprintMismatch(self.fingerprint, fingerprint),
+            Err(e) => e,
+        };
+        if !self.reconnect {
+            return Err(error);
+        }
+
+        // The passphrase is also sent when enumerating, without it the fingerprint of a device
+        // needing one isn't known. The builder's error for such a device tells why it wasn't
+        // found, otherwise the device is just missing and the original error is kept.
+        let reconnected = HWIClientBuilder::<T>::new()
+            .chain(self.chain.clone())
+            .expert(self.expert)
+            .passphrase(self.passphrase.clone())
+            .fingerprint(self.fingerprint)
+            .timeouts(client.timeouts())
+            .build()
+            .map_err(|e| if e.code().is_some() { e } else { error })?;
+        match reconnected.get_master_fingerprint()? {
+            fingerprint if fingerprint == self.fingerprint => {}
+            fingerprint => return Err(Error::FingerprintMismatch(self.fingerprint, fingerprint)),
+        }
+        *client = reconnected;
+        Ok(client)
+    }
+}
+
+/// Creates a watch-only [`Wallet`] for the `account` of the device behind `client`, using its
+/// descriptors of `address_type`, with an [`HWISigner`] for both keychains.
+///
+/// The signer is returned as well, e.g. to read [`HWISigner::signatures_added`] after signing.
+///
+/// Fails with [`Error::InvalidValue`] if `client` was opened for another chain than `network`, as
+/// its keys wouldn't belong to the wallet.
+///
+/// ```no_run
+/// # use hwi::error::Error;
+/// # use hwi::signer::create_wallet;
+/// # use hwi::types::HWIAddressType;
+/// # use hwi::PythonHWIClient;
+/// # use bdk_wallet::bitcoin::Network;
+/// # fn main() -> Result<(), Error> {
+/// let device = PythonHWIClient::enumerate()?.remove(0)?;
+/// let client = PythonHWIClient::get_client(&device, false, Network::Testnet.into())?;
+/// let (mut wallet, _signer) = create_wallet(client, Network::Testnet, 0, HWIAddressType::Wit)?;
+/// println!("{}", wallet.reveal_next_address(bdk_wallet::KeychainKind::External));
+/// # Ok(())
+/// # }
+/// ```
+pub fn create_wallet<T: HWIImplementation>(
+    client: HWIClient<T>,
+    network: Network,
+    account: u32,
+    address_type: HWIAddressType,
+) -> Result<(Wallet, Arc<HWISigner<T>>), Error> {
+    if client.chain().network() != network {
+        return Err(Error::InvalidValue(format!(
+            "the client is opened for {} but the wallet is for {}",
+            client.chain().network(),
+            network
+        )));
+    }
+    let descriptors = client.get_descriptors::<String>(Some(account))?;
+    let external = find_descriptor(descriptors.receive, &address_type)?;
+    let internal = find_descriptor(descriptors.internal, &address_type)?;
+
+    let mut wallet = Wallet::create(external, internal)
+        .network(network)
+        .create_wallet_no_persist()?;
+    let signer = Arc::new(HWISigner::from_client(client, network.into())?);
+    wallet.add_signer(KeychainKind::External, SignerOrdering(200), signer.clone());
+    wallet.add_signer(KeychainKind::Internal, SignerOrdering(200), signer.clone());
+    Ok((wallet, signer))
+}
+
+/// Picks the descriptor of `address_type` among the ones returned by `getdescriptors`.
+fn find_descriptor(
+    descriptors: Vec<String>,
+    address_type: &HWIAddressType,
+) -> Result<String, Error> {
+    let prefix = match address_type {
+        HWIAddressType::Legacy => "pkh(",
+        HWIAddressType::Sh_Wit => "sh(wpkh(",
+        HWIAddressType::Wit => "wpkh(",
+        HWIAddressType::Tap => "tr(",
+    };
+    descriptors
+        .into_iter()
+        .find(|descriptor| descriptor.starts_with(prefix))
+        .ok_or_else(|| {
+            Error::InvalidValue(format!("the device has no {} descriptor", address_type))
+        })
+}
+
+fn is_taproot(input: &Input) -> bool {
+    input.tap_internal_key.is_some() || input.tap_merkle_root.is_some()
+}
+
+/// Refuses the PSBTs `sign_options` don't allow to sign, like BDK's own signers do, checking the
+/// inputs flagged in `ours`.
+fn check_psbt(psbt: &Psbt, ours: &[bool], sign_options: &SignOptions) -> Result<(), SignerError> {
+    let inputs = psbt.inputs.iter().zip(&psbt.unsigned_tx.input).zip(ours);
+    for ((input, txin), _) in inputs.filter(|(_, ours)| **ours) {
+        match &input.non_witness_utxo {
+            // Segwit v0 signatures only commit to the amount of the input being signed, the
+            // previous transaction proves the amounts of the others
+            None if !sign_options.trust_witness_utxo && !is_taproot(input) => {
+                return Err(SignerError::MissingNonWitnessUtxo)
+            }
+            Some(tx) if tx.compute_txid() != txin.previous_output.txid => {
+                return Err(SignerError::InvalidNonWitnessUtxo)
+            }
+            _ => {}
+        }
+        let standard_sighash = match input.sighash_type {
+            None => true,
+            Some(sighash_type) => {
+                sighash_type == EcdsaSighashType::All.into()
+                    || sighash_type == TapSighashType::All.into()
+                    // SIGHASH_DEFAULT only exists for taproot, it's invalid for ECDSA
+                    || (sighash_type == TapSighashType::Default.into() && is_taproot(input))
+            }
+        };
+        if !sign_options.allow_all_sighashes && !standard_sighash {
+            return Err(SignerError::NonStandardSighash);
+        }
+    }
+    Ok(())
+}
+
+fn leaf_allowed(leaf_hash: &TapLeafHash, sign_options: &SignOptions) -> bool {
+    match &sign_options.tap_leaves_options {
+        TapLeavesOptions::All => true,
+        TapLeavesOptions::Include(leaves) => leaves.contains(leaf_hash),
+        TapLeavesOptions::Exclude(leaves) => !leaves.contains(leaf_hash),
+        TapLeavesOptions::None => false,
+    }
+}
+
+/// Hides from the device the taproot keys `sign_options` don't allow to sign with.
+fn filter_tap_keys(psbt: &mut Psbt, sign_options: &SignOptions) {
+    for input in &mut psbt.inputs {
+        input.tap_key_origins.retain(|_, (leaf_hashes, _)| {
+            if leaf_hashes.is_empty() {
+                // The internal key, signing for the key path
+                return sign_options.sign_with_tap_internal_key;
+            }
+            leaf_hashes.retain(|leaf_hash| leaf_allowed(leaf_hash, sign_options));
+            !leaf_hashes.is_empty()
+        });
+    }
+}
+
+/// Drops the taproot signatures the device added although `sign_options` don't allow them.
+fn filter_tap_signatures(signed: &mut Psbt, original: &Psbt, sign_options: &SignOptions) {
+    for (input, original) in signed.inputs.iter_mut().zip(&original.inputs) {
+        if !sign_options.sign_with_tap_internal_key && original.tap_key_sig.is_none() {
+            input.tap_key_sig = None;
+        }
+        input.tap_script_sigs.retain(|key, _| {
+            original.tap_script_sigs.contains_key(key) || leaf_allowed(&key.1, sign_options)
+        });
+    }
+}
+
+/// Fails if the device returned a transaction different from the one it was asked to sign.
+pub(crate) fn check_unsigned_tx(
+    original: &Transaction,
+    signed: &Transaction,
+) -> Result<(), SignerError> {
+    let altered = if signed.output != original.output {
+        "outputs"
+    } else if signed.lock_time != original.lock_time {
+        "locktime"
+    } else if signed.input.len() != original.input.len()
+        || signed
+            .input
+            .iter()
+            .zip(&original.input)
+            .any(|(signed, original)| signed.sequence != original.sequence)
+    {
+        "sequence numbers"
+    } else if signed != original {
+        "transaction"
+    } else {
+        return Ok(());
+    };
+    Err(SignerError::External(format!(
+        "The hardware wallet altered the {} of the transaction",
+        altered
+    )))
+}
+
+/// Returns whether the spending conditions can be checked without knowing the chain: BDK
+/// finalizes the timelocked transactions itself, knowing the current height.
+fn can_finalize(psbt: &Psbt, sign_options: &SignOptions) -> bool {
+    let absolute = match psbt.unsigned_tx.lock_time {
+        LockTime::Blocks(height) if height.to_consensus_u32() > 0 => sign_options
+            .assume_height
+            .map_or(false, |assumed| assumed >= height.to_consensus_u32()),
+        LockTime::Blocks(_) => true,
+        LockTime::Seconds(_) => false,
+    };
+    let relative = psbt
+        .unsigned_tx
+        .input
+        .iter()
+        .any(|txin| txin.sequence.is_relative_lock_time());
+    sign_options.try_finalize && absolute && !relative
 }
 
-impl SignerCommon for HWISigner {
+impl<T: HWIImplementation> SignerCommon for HWISigner<T> {
     fn id(&self, _secp: &Secp256k1<All>) -> SignerId {
         SignerId::Fingerprint(self.fingerprint)
     }
 }
 
-impl TransactionSigner for HWISigner {
+impl<T: HWIImplementation> TransactionSigner for HWISigner<T> {
     fn sign_transaction(
         &self,
         psbt: &mut Psbt,
-        _sign_options: &bdk_wallet::SignOptions,
-        _secp: &Secp256k1<All>,
+        sign_options: &SignOptions,
+        secp: &Secp256k1<All>,
     ) -> Result<(), SignerError> {
-        psbt.combine(
-            self.client
-                .sign_tx(psbt)
-                .map_err(|e| {
-                    SignerError::External(format!("While signing with hardware wallet: {}", e))
-                })?
-                .psbt,
-        )
-        .expect("Failed to combine HW signed psbt with passed PSBT");
-        Ok(())
+        self.sign_psbt(psbt, sign_options, secp).map(|_| ())
     }
 }
@@ -1,23 +1,27 @@
 use core::fmt;
 use std::convert::TryFrom;
 use std::fmt::{Display, Formatter};
-use std::ops::Deref;
+use std::ops::{Deref, RangeInclusive};
 use std::str::FromStr;
+use std::time::Duration;
 
-use bitcoin::address::{Address, NetworkUnchecked};
-use bitcoin::bip32::{Fingerprint, Xpub};
+use bitcoin::address::{Address, AddressType, NetworkUnchecked};
+use bitcoin::bip32::{DerivationPath, Fingerprint, Xpub};
+use bitcoin::key::{PublicKey, TapTweak, XOnlyPublicKey};
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::sign_message::{signed_msg_hash, MessageSignature};
 use bitcoin::Network;
 use bitcoin::Psbt;
 
-use pyo3::types::PyModule;
-use pyo3::{IntoPy, PyObject};
-use serde::{Deserialize, Deserializer};
+#[cfg(feature = "python")]
+use pyo3::{prelude::PyAnyMethods, types::PyModule, PyObject};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::value::Value;
 
 #[cfg(feature = "miniscript")]
 use miniscript::{Descriptor, DescriptorPublicKey};
-use pyo3::prelude::PyAnyMethods;
 
-use crate::error::{Error, ErrorCode};
+use crate::error::{Error, ErrorCode, HWIErrorDetails};
 
 #[derive(Clone, Eq, PartialEq, Debug, Deserialize)]
 pub struct HWIExtendedPubKey {
@@ -55,6 +59,76 @@ impl Deref for HWISignature {
     }
 }
 
+impl HWISignature {
+    /// Returns the signature as a recoverable [`MessageSignature`].
+    ///
+    /// Signatures with a BIP 137 segwit header, as produced by some devices, are accepted too.
+    pub fn message_signature(&self) -> Result<MessageSignature, Error> {
+        let mut bytes = self.signature.clone();
+        if let Some(header) = bytes.first_mut() {
+            // 35-38 is P2SH-P2WPKH and 39-42 is P2WPKH, both implying a compressed key
+            if (35..=42).contains(header) {
+                *header = 31 + ((*header - 27) & 0x03);
+            }
+        }
+        Ok(MessageSignature::from_slice(&bytes)?)
+    }
+
+    /// Recovers the public key that signed `message`.
+    pub fn recover_pubkey(&self, message: &str) -> Result<PublicKey, Error> {
+        let secp = Secp256k1::verification_only();
+        Ok(self
+            .message_signature()?
+            .recover_pubkey(&secp, signed_msg_hash(message))?)
+    }
+
+    /// Returns whether `message` was signed by the key of `address`.
+    ///
+    /// P2PKH, P2SH-P2WPKH, P2WPKH and P2TR (key path) addresses are supported; for any other
+    /// address this returns `false`.
+    pub fn verify_message(&self, address: &Address, message: &str) -> Result<bool, Error> {
+        let pubkey = self.recover_pubkey(message)?;
+        match address.address_type() {
+            Some(AddressType::P2tr) => {
+                let secp = Secp256k1::verification_only();
+                let (tweaked, _) = XOnlyPublicKey::from(pubkey.inner).tap_tweak(&secp, None);
+                Ok(address.is_related_to_xonly_pubkey(&tweaked.to_x_only_public_key()))
+            }
+            Some(AddressType::P2pkh) | Some(AddressType::P2sh) | Some(AddressType::P2wpkh) => {
+                Ok(address.is_related_to_pubkey(&pubkey))
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+/// A wallet policy registered on a device, to be passed back when displaying its addresses or
+/// signing for it.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct HWIWalletPolicy {
+    /// Name the policy was registered with, shown by the device.
+    pub name: String,
+    /// Descriptor of the policy, without checksum.
+    pub descriptor: String,
+    /// HMAC returned by the device when registering the policy, proving it was approved.
+    pub hmac: Vec<u8>,
+}
+
+// Used internally to deserialize the result of `hwi register`.
+#[derive(Clone, Eq, PartialEq, Debug, Deserialize)]
+pub(crate) struct HWIPolicyRegistration {
+    #[serde(deserialize_with = "from_hex")]
+    pub hmac: Vec<u8>,
+}
+
+fn from_hex<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+    use bitcoin::hex::FromHex;
+
+    let hex_string = String::deserialize(d)?;
+    Vec::from_hex(&hex_string)
+        .map_err(|_| serde::de::Error::custom("error while deserializing hmac"))
+}
+
 #[derive(Clone, Eq, PartialEq, Debug, Deserialize)]
 pub struct HWIAddress {
     pub address: Address<NetworkUnchecked>,
@@ -93,16 +167,193 @@ where
     pub receive: Vec<T>,
 }
 
-#[derive(Clone, Eq, PartialEq, Debug, Deserialize)]
-pub struct HWIKeyPoolElement {
-    pub desc: String,
-    pub range: Vec<u32>,
-    pub timestamp: String,
+/// An element of the result of `getkeypool`, in the format of Bitcoin Core `importmulti` and
+/// `importdescriptors` requests.
+#[derive(Clone, Eq, PartialEq, Debug, Deserialize, Serialize)]
+pub struct HWIKeyPoolElement<T = String>
+where
+    T: ToDescriptor,
+{
+    pub desc: T,
+    #[serde(with = "range_array")]
+    pub range: RangeInclusive<u32>,
+    pub timestamp: HWITimestamp,
     pub internal: bool,
     pub keypool: bool,
     pub watchonly: bool,
 }
 
+/// (De)serializes a range as a `[start, end]` array.
+mod range_array {
+    use std::ops::RangeInclusive;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(range: &RangeInclusive<u32>, s: S) -> Result<S::Ok, S::Error> {
+        [*range.start(), *range.end()].serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<RangeInclusive<u32>, D::Error> {
+        let [start, end] = <[u32; 2]>::deserialize(d)?;
+        Ok(start..=end)
+    }
+}
+
+/// Time from which the keys of a [`HWIKeyPoolElement`] should be rescanned.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum HWITimestamp {
+    /// The keys were just created, there is no need to rescan.
+    Now,
+    /// UNIX time in seconds.
+    Time(u64),
+}
+
+impl Serialize for HWITimestamp {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        match self {
+            HWITimestamp::Now => s.serialize_str("now"),
+            HWITimestamp::Time(time) => s.serialize_u64(*time),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for HWITimestamp {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Timestamp {
+            Now(String),
+            Time(u64),
+        }
+
+        match Timestamp::deserialize(d)? {
+            Timestamp::Now(s) if s == "now" => Ok(HWITimestamp::Now),
+            Timestamp::Now(s) => Err(serde::de::Error::custom(format!("invalid timestamp {}", s))),
+            Timestamp::Time(time) => Ok(HWITimestamp::Time(time)),
+        }
+    }
+}
+
+/// Timeouts of the calls made through a [`HWIClient`](crate::HWIClient), `None` meaning no
+/// limit, which is the default.
+///
+/// ```
+/// # use hwi::types::HWITimeouts;
+/// # use std::time::Duration;
+/// let timeouts = HWITimeouts::new()
+///     .query(Duration::from_secs(10))
+///     .confirmation(Duration::from_secs(120));
+/// ```
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
+pub struct HWITimeouts {
+    /// Calls only reading from the device, such as `get_xpub` or `get_descriptors`.
+    pub query: Option<Duration>,
+    /// Calls waiting for the user to confirm on the device, such as `sign_tx` or
+    /// `display_address_with_path`.
+    pub confirmation: Option<Duration>,
+}
+
+impl HWITimeouts {
+    /// Creates timeouts without any limit.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the timeout of the calls only reading from the device.
+    pub fn query(mut self, timeout: Duration) -> Self {
+        self.query = Some(timeout);
+        self
+    }
+
+    /// Sets the timeout of the calls waiting for the user to confirm on the device.
+    pub fn confirmation(mut self, timeout: Duration) -> Self {
+        self.confirmation = Some(timeout);
+        self
+    }
+}
+
+/// Parameters of [`HWIClient::get_keypool_with`](crate::HWIClient::get_keypool_with).
+///
+/// ```
+/// # use hwi::types::{HWIAddressType, KeypoolRequest};
+/// let request = KeypoolRequest::new(0, 100)
+///     .internal(true)
+///     .addr_type(HWIAddressType::Tap)
+///     .account(1);
+/// ```
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct KeypoolRequest {
+    /// `keypool` value in the result, see the `importmulti` documentation of Bitcoin Core.
+    pub keypool: bool,
+    /// Whether to return internal (change) keys rather than external ones.
+    pub internal: bool,
+    /// Address type of the keys.
+    pub addr_type: HWIAddressType,
+    /// Whether to return keys for every address type, ignoring `addr_type`.
+    pub addr_all: bool,
+    /// BIP 43 account of the keys, 0 if not set.
+    pub account: Option<u32>,
+    /// Derivation path of the keys, overriding `addr_type` and `account`.
+    pub path: Option<DerivationPath>,
+    /// Index of the first key.
+    pub start: u32,
+    /// Index of the last key.
+    pub end: u32,
+}
+
+impl KeypoolRequest {
+    /// Requests the external native segwit keys from `start` to `end` of account 0, with
+    /// `keypool` set.
+    pub fn new(start: u32, end: u32) -> Self {
+        KeypoolRequest {
+            keypool: true,
+            internal: false,
+            addr_type: HWIAddressType::Wit,
+            addr_all: false,
+            account: None,
+            path: None,
+            start,
+            end,
+        }
+    }
+
+    /// Sets the `keypool` value in the result.
+    pub fn keypool(mut self, keypool: bool) -> Self {
+        self.keypool = keypool;
+        self
+    }
+
+    /// Sets whether to return internal (change) keys.
+    pub fn internal(mut self, internal: bool) -> Self {
+        self.internal = internal;
+        self
+    }
+
+    /// Sets the address type of the keys.
+    pub fn addr_type(mut self, addr_type: HWIAddressType) -> Self {
+        self.addr_type = addr_type;
+        self
+    }
+
+    /// Sets whether to return keys for every address type.
+    pub fn addr_all(mut self, addr_all: bool) -> Self {
+        self.addr_all = addr_all;
+        self
+    }
+
+    /// Sets the BIP 43 account of the keys.
+    pub fn account(mut self, account: u32) -> Self {
+        self.account = Some(account);
+        self
+    }
+
+    /// Sets the derivation path of the keys.
+    pub fn path(mut self, path: DerivationPath) -> Self {
+        self.path = Some(path);
+        self
+    }
+}
+
 #[derive(Clone, Eq, PartialEq, Debug, Deserialize)]
 #[allow(non_camel_case_types)]
 pub enum HWIAddressType {
@@ -112,42 +363,57 @@ pub enum HWIAddressType {
     Tap,
 }
 
-impl IntoPy<PyObject> for HWIAddressType {
-    fn into_py(self, py: pyo3::Python) -> PyObject {
-        let addrtype = PyModule::import_bound(py, "hwilib.common")
-            .unwrap()
-            .getattr("AddressType")
-            .unwrap();
-        match self {
-            HWIAddressType::Legacy => addrtype.get_item("LEGACY").unwrap().into(),
-            HWIAddressType::Sh_Wit => addrtype.get_item("SH_WIT").unwrap().into(),
-            HWIAddressType::Wit => addrtype.get_item("WIT").unwrap().into(),
-            HWIAddressType::Tap => addrtype.get_item("TAP").unwrap().into(),
-        }
+impl Display for HWIAddressType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            HWIAddressType::Legacy => "legacy",
+            HWIAddressType::Sh_Wit => "sh_wit",
+            HWIAddressType::Wit => "wit",
+            HWIAddressType::Tap => "tap",
+        };
+        fmt::Display::fmt(name, f)
+    }
+}
+
+#[cfg(feature = "python")]
+impl HWIAddressType {
+    /// Returns the matching `hwilib.common.AddressType`.
+    pub(crate) fn to_py(&self, py: pyo3::Python) -> Result<PyObject, Error> {
+        let addrtype = PyModule::import_bound(py, "hwilib.common")?.getattr("AddressType")?;
+        let name = match self {
+            HWIAddressType::Legacy => "LEGACY",
+            HWIAddressType::Sh_Wit => "SH_WIT",
+            HWIAddressType::Wit => "WIT",
+            HWIAddressType::Tap => "TAP",
+        };
+        Ok(addrtype.get_item(name)?.into())
     }
 }
 
 #[derive(Clone, Eq, PartialEq, Debug, Deserialize)]
 pub struct HWIChain(bitcoin::Network);
 
-impl IntoPy<PyObject> for HWIChain {
-    fn into_py(self, py: pyo3::Python) -> PyObject {
+#[cfg(feature = "python")]
+impl HWIChain {
+    /// Returns the matching `hwilib.common.Chain`.
+    pub(crate) fn to_py(&self, py: pyo3::Python) -> Result<PyObject, Error> {
         use bitcoin::Network::*;
 
-        let chain = PyModule::import_bound(py, "hwilib.common")
-            .unwrap()
-            .getattr("Chain")
-            .unwrap();
-        match self.0 {
-            Bitcoin => chain.get_item("MAIN").unwrap().into(),
-            Testnet => chain.get_item("TEST").unwrap().into(),
-            Regtest => chain.get_item("REGTEST").unwrap().into(),
-            Signet => chain.get_item("SIGNET").unwrap().into(),
-            // This handles non_exhaustive on Network which is only there to future proof
-            // rust-bitcoin, will need to check this when upgrading rust-bitcoin.
-            // Sane as of rust-bitcoin v0.30.0
-            _ => panic!("unknown network"),
-        }
+        let name = match self.0 {
+            Bitcoin => "MAIN",
+            Testnet => "TEST",
+            Regtest => "REGTEST",
+            Signet => "SIGNET",
+            // Network is non_exhaustive, new networks are unknown to HWI
+            network => {
+                return Err(Error::InvalidValue(format!(
+                    "network {} is not supported by HWI",
+                    network
+                )))
+            }
+        };
+        let chain = PyModule::import_bound(py, "hwilib.common")?.getattr("Chain")?;
+        Ok(chain.get_item(name)?.into())
     }
 }
 
@@ -157,6 +423,25 @@ impl From<Network> for HWIChain {
     }
 }
 
+impl HWIChain {
+    /// Returns the network this chain refers to.
+    pub fn network(&self) -> Network {
+        self.0
+    }
+}
+
+/// BIP 39 passphrase used when opening a device.
+#[derive(Clone, Eq, PartialEq, Debug, Default)]
+pub enum HWIPassphrase {
+    /// No passphrase.
+    #[default]
+    Empty,
+    /// Passphrase sent by the host.
+    Host(String),
+    /// Passphrase typed by the user on the device, for devices supporting it.
+    OnDevice,
+}
+
 #[cfg(test)]
 pub const TESTNET: HWIChain = HWIChain(Network::Testnet);
 
@@ -191,30 +476,62 @@ impl TryFrom<HWIDeviceInternal> for HWIDevice {
     type Error = Error;
     fn try_from(h: HWIDeviceInternal) -> Result<HWIDevice, Error> {
         match h.error {
-            Some(e) => {
-                let code = h.code.and_then(|c| ErrorCode::try_from(c).ok());
-                Err(Error::Hwi(e, code))
+            Some(message) => Err(HWIErrorResponse {
+                error: message,
+                code: h.code,
             }
+            .into()),
             // When HWIDeviceInternal contains errors, some fields might be missing
             // (depending on the error, hwi might not be able to know all of them).
-            // When there's no error though, all the fields must be present, and
-            // for this reason we expect here.
+            // When there's no error though, all the fields must be present.
             None => Ok(HWIDevice {
-                device_type: HWIDeviceType::from(
-                    h.device_type.expect("Device type should be here"),
-                ),
-                model: h.model.expect("Model should be here"),
-                path: h.path.expect("Path should be here"),
-                needs_pin_sent: h.needs_pin_sent.expect("needs_pin_sent should be here"),
-                needs_passphrase_sent: h
-                    .needs_passphrase_sent
-                    .expect("needs_passphrase_sent should be here"),
-                fingerprint: h.fingerprint.expect("Fingerprint should be here"),
+                device_type: HWIDeviceType::from(required(h.device_type, "type")?),
+                model: required(h.model, "model")?,
+                path: required(h.path, "path")?,
+                needs_pin_sent: required(h.needs_pin_sent, "needs_pin_sent")?,
+                needs_passphrase_sent: required(h.needs_passphrase_sent, "needs_passphrase_sent")?,
+                fingerprint: required(h.fingerprint, "fingerprint")?,
             }),
         }
     }
 }
 
+/// Object returned by HWI in place of the output of a failed command.
+#[derive(Clone, Eq, PartialEq, Debug, Deserialize)]
+pub(crate) struct HWIErrorResponse {
+    pub error: String,
+    pub code: Option<i8>,
+}
+
+impl HWIErrorResponse {
+    /// Returns the error reported by `output`, if it is an object with an `error` field.
+    pub(crate) fn check(output: &Value) -> Result<(), Error> {
+        // The derived implementation would also accept an array, e.g. a list of keys
+        if !matches!(output, Value::Object(object) if object.contains_key("error")) {
+            return Ok(());
+        }
+        match HWIErrorResponse::deserialize(output) {
+            Ok(response) => Err(response.into()),
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+impl From<HWIErrorResponse> for Error {
+    fn from(response: HWIErrorResponse) -> Self {
+        Error::from_details(HWIErrorDetails {
+            message: response.error,
+            code: response.code.and_then(|c| ErrorCode::try_from(c).ok()),
+            traceback: None,
+        })
+    }
+}
+
+/// Returns the value of a field HWI must have set, or an error naming it.
+fn required<T>(value: Option<T>, field: &str) -> Result<T, Error> {
+    value.ok_or_else(|| Error::InvalidValue(format!("device is missing the `{}` field", field)))
+}
+
 #[derive(Clone, Eq, PartialEq, Debug, Deserialize)]
 pub struct HWIStatus {
     pub success: bool,
@@ -279,6 +596,7 @@ impl Display for HWIDeviceType {
     }
 }
 
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
 pub enum LogLevel {
     DEBUG,
     INFO,
@@ -295,3 +613,89 @@ pub enum HWIWordCount {
     W18 = 18,
     W24 = 24,
 }
+
+/// A backend through which [`HWIClient`](crate::HWIClient) reaches HWI.
+///
+/// Every method returns the JSON document HWI produces for the corresponding command, which
+/// `HWIClient` then deserializes into the types of this module. The default implementation is
+/// `PythonHWI`, available with the `python` feature.
+pub trait HWIImplementation: fmt::Debug + Send + Sync + Sized + 'static {
+    /// Lists all HW devices currently connected, getting the fingerprint of the devices needing
+    /// a passphrase with `password`.
+    fn enumerate(password: Option<&str>) -> Result<String, Error>;
+
+    /// Opens a client for a device returned by `enumerate`, using `passphrase`.
+    fn get_client(
+        device: &HWIDevice,
+        expert: bool,
+        chain: HWIChain,
+        passphrase: &HWIPassphrase,
+    ) -> Result<Self, Error>;
+
+    /// Opens a client for the first device matching `device_type` and/or `fingerprint`.
+    fn find_device(
+        password: Option<&str>,
+        device_type: Option<HWIDeviceType>,
+        fingerprint: Option<&str>,
+        expert: bool,
+        chain: Network,
+    ) -> Result<Self, Error>;
+
+    /// Returns the chain the client was opened for.
+    fn chain(&self) -> HWIChain;
+
+    fn get_master_xpub(&self, addrtype: HWIAddressType, account: u32) -> Result<String, Error>;
+
+    /// Signs `psbt`, for the registered `policy` if any.
+    fn sign_tx(&self, psbt: &Psbt, policy: Option<&HWIWalletPolicy>) -> Result<String, Error>;
+
+    fn get_xpub(&self, path: &DerivationPath, expert: bool) -> Result<String, Error>;
+
+    fn sign_message(&self, message: &str, path: &DerivationPath) -> Result<String, Error>;
+
+    fn get_keypool(&self, request: &KeypoolRequest) -> Result<String, Error>;
+
+    fn get_descriptors(&self, account: Option<u32>) -> Result<String, Error>;
+
+    /// Displays the address of `descriptor`, which belongs to the registered `policy` if any.
+    /// `descriptor` is passed without its checksum.
+    fn display_address_with_desc(
+        &self,
+        descriptor: &str,
+        policy: Option<&HWIWalletPolicy>,
+    ) -> Result<String, Error>;
+
+    /// Registers the wallet policy described by `descriptor` (without checksum) under `name`.
+    fn register_policy(&self, name: &str, descriptor: &str) -> Result<String, Error>;
+
+    fn display_address_with_path(
+        &self,
+        path: &DerivationPath,
+        address_type: HWIAddressType,
+    ) -> Result<String, Error>;
+
+    fn install_udev_rules(source: &str, location: &str) -> Result<String, Error>;
+
+    fn set_log_level(level: LogLevel) -> Result<(), Error>;
+
+    fn toggle_passphrase(&self) -> Result<String, Error>;
+
+    fn setup_device(&self, label: &str, passphrase: &str) -> Result<String, Error>;
+
+    fn restore_device(&self, label: &str, word_count: u8) -> Result<String, Error>;
+
+    fn backup_device(&self, label: &str, backup_passphrase: &str) -> Result<String, Error>;
+
+    fn wipe_device(&self) -> Result<String, Error>;
+
+    /// Asks the device to show the scrambled PIN matrix.
+    fn prompt_pin(&self) -> Result<String, Error>;
+
+    /// Sends the PIN, given as the positions of its digits in the matrix shown by the device.
+    fn send_pin(&self, pin: &str) -> Result<String, Error>;
+
+    /// Returns the version of HWI used by this implementation, if it is available.
+    fn get_version() -> Option<String>;
+
+    fn install_hwilib(version: Option<&str>) -> Result<(), Error>;
+}
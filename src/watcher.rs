@@ -0,0 +1,213 @@
+//! Notifications of devices being plugged in, unplugged, locked and unlocked.
+//!
+//! [`DeviceWatcher`] enumerates the devices periodically on a background thread and compares
+//! successive results, emitting a [`DeviceEvent`] for every change. Devices are identified by
+//! their path, as the fingerprint of a locked device is unknown.
+//!
+//! HWI enumerates by opening every device, which could interrupt a call in progress. The
+//! enumeration is therefore postponed while a [`SharedHWIClient`](crate::SharedHWIClient) holds
+//! a device, which also delays the events of the other devices until it is released. The watcher
+//! never locks the devices itself, so a call starting right as an enumeration begins isn't
+//! excluded. Calls made through a plain [`HWIClient`] aren't visible to the watcher: use a
+//! `SharedHWIClient` for the devices used while watching.
+
+use std::convert::TryFrom;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+
+use crate::error::{Error, ErrorCode};
+use crate::interface::HWIClient;
+use crate::shared_interface::any_device_busy;
+use crate::types::{HWIDevice, HWIDeviceInternal, HWIImplementation};
+
+/// A change in the devices reported by HWI.
+#[derive(Debug)]
+pub enum DeviceEvent {
+    /// A device was plugged in at `path`. `device` is `None` when HWI couldn't fully identify it,
+    /// e.g. because it's locked, and `Some` once it can, or when its details change.
+    Connected {
+        path: String,
+        device: Option<HWIDevice>,
+    },
+    /// The device at `path` was unplugged.
+    Disconnected { path: String },
+    /// The device at `path` needs its PIN to be sent.
+    Locked { path: String },
+    /// The device at `path` was unlocked.
+    Unlocked {
+        path: String,
+        device: Option<HWIDevice>,
+    },
+    /// HWI reported an error for the device at `path`, or for the whole enumeration if `path` is
+    /// `None`. Errors are only reported again once they change.
+    Error { path: Option<String>, error: Error },
+}
+
+/// What was last seen at a path.
+#[derive(Debug)]
+struct SeenDevice {
+    path: String,
+    device: Option<HWIDevice>,
+    locked: bool,
+    error: Option<String>,
+}
+
+/// Compares successive enumerations and returns the events describing the changes.
+#[derive(Debug, Default)]
+pub(crate) struct DeviceTracker {
+    devices: Vec<SeenDevice>,
+    errors: Vec<String>,
+}
+
+impl DeviceTracker {
+    pub(crate) fn update(
+        &mut self,
+        enumeration: Result<Vec<HWIDeviceInternal>, Error>,
+    ) -> Vec<DeviceEvent> {
+        let mut events = Vec::new();
+        let entries = match enumeration {
+            Ok(entries) => entries,
+            Err(error) => {
+                // Keep the devices as they were, nothing is known about them
+                let message = error.to_string();
+                if !self.errors.contains(&message) {
+                    self.errors = vec![message];
+                    events.push(DeviceEvent::Error { path: None, error });
+                }
+                return events;
+            }
+        };
+
+        let mut errors = Vec::new();
+        let mut devices = Vec::new();
+        for entry in entries {
+            let path = match entry.path.clone() {
+                Some(path) => path,
+                None => {
+                    if let Err(error) = HWIDevice::try_from(entry) {
+                        let message = error.to_string();
+                        if !self.errors.contains(&message) {
+                            events.push(DeviceEvent::Error { path: None, error });
+                        }
+                        errors.push(message);
+                    }
+                    continue;
+                }
+            };
+            let locked = entry.needs_pin_sent.unwrap_or(false);
+            let (device, error) = match HWIDevice::try_from(entry) {
+                Ok(device) => (Some(device), None),
+                // A locked device can't be identified, which Locked already tells
+                Err(e) if locked && e.code() == Some(ErrorCode::DeviceNotReady) => (None, None),
+                Err(e) => (None, Some(e)),
+            };
+
+            let previous = self
+                .devices
+                .iter()
+                .position(|seen| seen.path == path)
+                .map(|i| self.devices.swap_remove(i));
+            match &previous {
+                None => {
+                    events.push(DeviceEvent::Connected {
+                        path: path.clone(),
+                        device: device.clone(),
+                    });
+                    if locked {
+                        events.push(DeviceEvent::Locked { path: path.clone() });
+                    }
+                }
+                Some(seen) if seen.locked != locked => {
+                    if locked {
+                        events.push(DeviceEvent::Locked { path: path.clone() });
+                    } else {
+                        events.push(DeviceEvent::Unlocked {
+                            path: path.clone(),
+                            device: device.clone(),
+                        });
+                    }
+                }
+                Some(seen) if device.is_some() && seen.device != device => {
+                    events.push(DeviceEvent::Connected {
+                        path: path.clone(),
+                        device: device.clone(),
+                    });
+                }
+                Some(_) => {}
+            }
+
+            let message = error.as_ref().map(|e| e.to_string());
+            let previous_message = previous.and_then(|seen| seen.error);
+            if let Some(error) = error {
+                if message != previous_message {
+                    events.push(DeviceEvent::Error {
+                        path: Some(path.clone()),
+                        error,
+                    });
+                }
+            }
+            devices.push(SeenDevice {
+                path,
+                device,
+                locked,
+                error: message,
+            });
+        }
+
+        for seen in self.devices.drain(..) {
+            events.push(DeviceEvent::Disconnected { path: seen.path });
+        }
+        self.devices = devices;
+        self.errors = errors;
+        events
+    }
+}
+
+/// Watches the devices enumerated through the implementation `T`, see the [module
+/// documentation](self).
+///
+/// The watcher stops when dropped.
+#[derive(Debug)]
+pub struct DeviceWatcher {
+    events: Receiver<DeviceEvent>,
+    _stop: Sender<()>,
+}
+
+impl DeviceWatcher {
+    /// Starts enumerating the devices every `interval`.
+    ///
+    /// Devices connected when the watcher starts are reported as
+    /// [`Connected`](DeviceEvent::Connected) by the first enumeration.
+    pub fn start<T: HWIImplementation + 'static>(interval: Duration) -> Self {
+        let (events_sender, events) = mpsc::channel();
+        let (stop, stopped) = mpsc::channel::<()>();
+        thread::spawn(move || {
+            let mut tracker = DeviceTracker::default();
+            loop {
+                // Leave the devices alone while they are in use, and try again later
+                if !any_device_busy() {
+                    for event in tracker.update(HWIClient::<T>::enumerate_internal(None)) {
+                        if events_sender.send(event).is_err() {
+                            return;
+                        }
+                    }
+                }
+                // Nothing is ever sent, the channel is disconnected once the watcher is dropped
+                match stopped.recv_timeout(interval) {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    _ => return,
+                }
+            }
+        });
+        DeviceWatcher {
+            events,
+            _stop: stop,
+        }
+    }
+
+    /// Returns the receiver of the events, in the order they happened.
+    pub fn events(&self) -> &Receiver<DeviceEvent> {
+        &self.events
+    }
+}